@@ -0,0 +1,175 @@
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::{self, JoinSet};
+
+use crate::export::Exports;
+use crate::server::{ServerError, MAX_IN_FLIGHT};
+use crate::session::{self, Payload, Session, Step};
+use crate::tls::{AsyncStream, Tls};
+use crate::transport::AsyncTransport;
+use crate::protocol::request as req;
+
+/// `Server` on tokio: the same negotiation and transmission, with the socket driven by the
+/// runtime and export I/O done on its blocking pool, so idle clients cost no thread.
+pub struct AsyncServer<T> {
+    session: Session,
+    stream: AsyncStream<T>,
+    tls: Tls,
+}
+
+impl<T: AsyncTransport> AsyncServer<T> {
+    pub async fn handshake(exports: Arc<Exports>, mut stream: T, chunk_size: u32, tls: Tls) -> Result<Self, ServerError> {
+        stream.write_all(&session::greeting()).await?;
+        stream.flush().await?;
+
+        let client_flags = stream.read_u32().await?;
+        let session = Session::new(exports, chunk_size, tls.mode, client_flags)?;
+
+        Ok ( Self {
+            session,
+            stream: AsyncStream::Plain(stream),
+            tls,
+        })
+    }
+
+    pub async fn oldstyle(exports: Arc<Exports>, mut stream: T, chunk_size: u32) -> Result<Self, ServerError> {
+        let (session, greeting) = Session::oldstyle(exports, chunk_size)?;
+
+        stream.write_all(&greeting).await?;
+        stream.flush().await?;
+
+        Ok ( Self {
+            session,
+            stream: AsyncStream::Plain(stream),
+            tls: Tls::off(),
+        })
+    }
+
+    pub async fn option_haggle(mut self) -> Result<Self, ServerError> {
+        let mut header = [0; session::OPTION_HEADER_LEN];
+        let mut replies = Vec::new();
+
+        loop {
+            self.stream.read_exact(&mut header).await?;
+            let (option, len) = session::option_header(&header)?;
+
+            let step = if len > session::MAX_OPTION_LEN {
+                let skipped = tokio::io::copy(&mut (&mut self.stream).take(len as u64), &mut tokio::io::sink()).await?;
+                if skipped < len as u64 { return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()) }
+                self.session.oversized_option(option, &mut replies)?
+            } else {
+                let mut raw = header[8..].to_vec();
+                raw.resize(8 + len, 0);
+                self.stream.read_exact(&mut raw[8..]).await?;
+                self.session.option(&raw, &mut replies)?
+            };
+
+            self.stream.write_all(&replies).await?;
+            self.stream.flush().await?;
+            replies.clear();
+
+            match step {
+                Step::Continue => {},
+                Step::StartTls => {
+                    self.stream = self.stream.start_tls(&self.tls).await
+                        .map_err(ServerError::TlsHandshake)?;
+                    self.session.tls_started(self.tls.async_peer(&self.stream));
+                },
+                Step::Transmission => break,
+                Step::Abort => return Err(ServerError::Abort),
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Transmission phase. Requests are read as they come and handled on the blocking pool,
+    /// replies go out in the order they complete, matched by handle.
+    pub async fn serve(self) -> Result<(), ServerError> {
+        if !self.session.is_ready() { return Err(ServerError::NotReady) }
+
+        let session = Arc::new(self.session);
+        let (mut reader, mut writer) = tokio::io::split(self.stream);
+        let (replies, mut reply_queue) = mpsc::channel::<Vec<u8>>(MAX_IN_FLIGHT);
+
+        let writing = tokio::spawn(async move {
+            while let Some(reply) = reply_queue.recv().await {
+                writer.write_all(&reply).await?;
+                writer.flush().await?;
+            }
+            Ok::<_, std::io::Error>(())
+        });
+
+        //  a handler finishes once its reply is queued for the writer
+        let mut in_flight = JoinSet::new();
+        let mut header_buf = vec![0; session.request_header_len()];
+        let result = loop {
+            let request = match read_request(&session, &mut reader, &mut header_buf).await {
+                Ok(request) => request,
+                Err(err) => break Err(err)
+            };
+
+            while let Some(handled) = in_flight.try_join_next() {
+                handled.expect("request handler panicked");
+            }
+            if session.is_barrier(&request) {
+                while let Some(handled) = in_flight.join_next().await {
+                    handled.expect("request handler panicked");
+                }
+            }
+            if let req::RequestType::Disc = request.type_ {
+                eprintln!("disconnecting");
+                break Ok(())
+            }
+
+            //  bounds the memory a client can tie up with requests read ahead
+            if in_flight.len() >= MAX_IN_FLIGHT {
+                if let Some(handled) = in_flight.join_next().await {
+                    handled.expect("request handler panicked");
+                }
+            }
+
+            let (handler, replies) = (Arc::clone(&session), replies.clone());
+            in_flight.spawn(async move {
+                //  the export is plain blocking file I/O, the whole reply is built off the runtime
+                let reply = task::spawn_blocking(move || handler.reply(request))
+                    .await.expect("request handler panicked");
+
+                //  a closed queue means the writer failed, which ends the session anyway
+                let _ = replies.send(reply).await;
+            });
+        };
+
+        //  requests already read are still answered
+        while let Some(handled) = in_flight.join_next().await {
+            handled.expect("request handler panicked");
+        }
+        drop(replies);
+        let written = writing.await.expect("reply writer panicked");
+
+        result.and(written.map_err(ServerError::from))
+    }
+}
+
+//  the header length is fixed once transmission starts
+async fn read_request<R: AsyncRead + Unpin>(session: &Session, stream: &mut R, header_buf: &mut [u8]) -> Result<req::Request, ServerError> {
+    stream.read_exact(header_buf).await?;
+    let mut request = session.parse_request(header_buf)?;
+
+    match session.payload(&request) {
+        Payload::None => {},
+        Payload::Read(len) => {
+            let mut payload = vec![0; len];
+            stream.read_exact(&mut payload).await?;
+            request.data = Some(payload);
+        },
+        Payload::Skip(len) => {
+            let skipped = tokio::io::copy(&mut stream.take(len), &mut tokio::io::sink()).await?;
+            if skipped < len { return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()) }
+        }
+    }
+
+    Ok(request)
+}
@@ -0,0 +1,264 @@
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::sync::Mutex;
+
+use crate::export::range_end;
+
+const MAGIC: &[u8; 8] = b"NBDDIRTY";
+const HEADER_LEN: u64 = 24;  //  magic, granularity, export size
+
+pub const DEFAULT_GRANULARITY: u64 = 64 << 10;
+
+/// Tracks which `granularity`-sized chunks of an export were changed since the bitmap
+/// was created or last cleared.
+///
+/// The bits live in a sidecar file: magic, granularity and size as big-endian u64s,
+/// then one bit per chunk. Newly dirty chunks are written through and synced before
+/// the data they cover, so a crash can't lose track of a write.
+pub struct DirtyBitmap {
+    pub name: String,
+    granularity: u64,
+    bits: Mutex<Vec<u8>>,
+    file: File,
+}
+
+impl DirtyBitmap {
+    /// Loads the bitmap from `path`, or starts a clean one if it doesn't exist or `clear` is set.
+    /// An existing file keeps its granularity unless it is cleared.
+    pub fn open(name: String, path: &str, granularity: u64, size: u64, clear: bool) -> io::Result<Self> {
+        if !granularity.is_power_of_two() || granularity < 512 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "bitmap granularity must be a power of two of at least 512"))
+        }
+
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+
+        let mut header = [0; HEADER_LEN as usize];
+        let loaded = !clear && file.metadata()?.len() >= HEADER_LEN && {
+            file.read_exact_at(&mut header, 0)?;
+            &header[..8] == MAGIC
+        };
+
+        let bitmap = if loaded {
+            let granularity = u64::from_be_bytes(header[8..16].try_into().unwrap());
+            let saved_size = u64::from_be_bytes(header[16..24].try_into().unwrap());
+            if !granularity.is_power_of_two() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("corrupt dirty bitmap {}", path)))
+            }
+
+            let mut bits = vec![0; bitmap_len(saved_size, granularity)];
+            file.read_exact_at(&mut bits, HEADER_LEN)?;
+
+            let bitmap = Self { name, granularity, bits: Mutex::new(bits), file };
+            //  the image may have been resized while nobody was tracking it
+            if saved_size != size {
+                bitmap.resize(saved_size, size)?;
+            }
+            bitmap
+        } else {
+            let bits = vec![0; bitmap_len(size, granularity)];
+            file.set_len(0)?;
+            file.write_all_at(&header_bytes(granularity, size), 0)?;
+            file.write_all_at(&bits, HEADER_LEN)?;
+
+            Self { name, granularity, bits: Mutex::new(bits), file }
+        };
+
+        Ok(bitmap)
+    }
+
+    /// Marks the chunks overlapping `offset..offset + len` as dirty
+    pub fn mark(&self, offset: u64, len: u64) -> io::Result<()> {
+        if len == 0 { return Ok(()) }
+
+        let mut bits = self.bits.lock().unwrap();
+        let first = offset / self.granularity;
+        let last = ((offset + len - 1) / self.granularity).min(bits.len() as u64 * 8 - 1);
+
+        let mut changed: Option<(usize, usize)> = None;
+        for chunk in first..=last {
+            let (byte, mask) = ((chunk / 8) as usize, 1 << (chunk % 8));
+            if bits[byte] & mask == 0 {
+                bits[byte] |= mask;
+                changed = Some(changed.map_or((byte, byte), |(start, _)| (start, byte)));
+            }
+        }
+
+        match changed {
+            Some((start, end)) => {
+                self.file.write_all_at(&bits[start..=end], HEADER_LEN + start as u64)?;
+                self.file.sync_data()
+            },
+            None => Ok(())
+        }
+    }
+
+    /// Forgets every change recorded so far
+    pub fn clear(&self) -> io::Result<()> {
+        let mut bits = self.bits.lock().unwrap();
+        bits.iter_mut().for_each(|byte| *byte = 0);
+        self.file.write_all_at(&bits, HEADER_LEN)?;
+        self.file.sync_data()
+    }
+
+    /// Follows an export resize, everything past the old end counts as changed
+    pub fn resize(&self, old_size: u64, new_size: u64) -> io::Result<()> {
+        {
+            let mut bits = self.bits.lock().unwrap();
+            bits.resize(bitmap_len(new_size, self.granularity), 0);
+
+            //  bits past the new end would come back dirty-looking after a later grow
+            let chunks = new_size.div_ceil(self.granularity);
+            for chunk in chunks..bits.len() as u64 * 8 {
+                bits[(chunk / 8) as usize] &= !(1 << (chunk % 8));
+            }
+
+            self.file.set_len(HEADER_LEN + bits.len() as u64)?;
+            self.file.write_all_at(&header_bytes(self.granularity, new_size), 0)?;
+            self.file.write_all_at(&bits, HEADER_LEN)?;
+        }
+
+        if new_size > old_size {
+            self.mark(old_size, new_size - old_size)?;
+        }
+        Ok(())
+    }
+
+    /// `(length, is_dirty)` runs from `offset`, covering `offset..offset + len` unless `max` runs end first
+    pub fn extents(&self, offset: u64, len: u64, max: usize) -> io::Result<Vec<(u64, bool)>> {
+        let end = range_end(offset, len)?;
+        let bits = self.bits.lock().unwrap();
+        let last = end.div_ceil(self.granularity);
+
+        let mut extents = Vec::new();
+        let mut pos = offset;
+        while pos < end && extents.len() < max {
+            let chunk = pos / self.granularity;
+            let dirty = is_set(&bits, chunk);
+            let next = run_end(&bits, chunk, dirty, last).saturating_mul(self.granularity).min(end);
+            extents.push((next - pos, dirty));
+            pos = next;
+        }
+
+        Ok(extents)
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+fn is_set(bits: &[u8], chunk: u64) -> bool {
+    bits.get((chunk / 8) as usize).is_some_and(|byte| byte & (1 << (chunk % 8)) != 0)
+}
+
+//  first chunk from `chunk` up to `last` that isn't `dirty`, skipping uniform bytes whole
+fn run_end(bits: &[u8], mut chunk: u64, dirty: bool, last: u64) -> u64 {
+    let uniform = if dirty { 0xff } else { 0 };
+    while chunk < last {
+        if chunk.is_multiple_of(8) && bits.get((chunk / 8) as usize) == Some(&uniform) {
+            chunk += 8;
+        } else if is_set(bits, chunk) == dirty {
+            chunk += 1;
+        } else {
+            break
+        }
+    }
+    chunk.min(last)
+}
+
+fn bitmap_len(size: u64, granularity: u64) -> usize {
+    size.div_ceil(granularity).div_ceil(8) as usize
+}
+
+fn header_bytes(granularity: u64, size: u64) -> Vec<u8> {
+    MAGIC.iter()
+        .chain(granularity.to_be_bytes().iter())
+        .chain(size.to_be_bytes().iter())
+        .copied().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchFile;
+
+    fn open(sidecar: &ScratchFile, granularity: u64, size: u64, clear: bool) -> DirtyBitmap {
+        DirtyBitmap::open("b".to_owned(), sidecar.path(), granularity, size, clear).unwrap()
+    }
+
+    #[test]
+    fn marks_whole_chunks() {
+        let sidecar = ScratchFile::new("marks.dirty", b"");
+        let bitmap = open(&sidecar, 4096, 64 << 10, false);
+
+        bitmap.mark(5000, 10).unwrap();
+        bitmap.mark(16383, 2).unwrap();
+        assert_eq!(bitmap.extents(0, 64 << 10, usize::MAX).unwrap(), [
+            (4096, false), (4096, true), (4096, false), (8192, true), (45056, false),
+        ]);
+        assert_eq!(bitmap.extents(6000, 10000, usize::MAX).unwrap(), [(2192, true), (4096, false), (3712, true)]);
+        assert_eq!(bitmap.extents(0, 64 << 10, 1).unwrap(), [(4096, false)]);
+        assert_eq!(bitmap.extents(6000, 10000, 2).unwrap(), [(2192, true), (4096, false)]);
+        assert!(bitmap.extents(u64::MAX, 2, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn long_runs_span_bytes() {
+        let sidecar = ScratchFile::new("runs.dirty", b"");
+        let bitmap = open(&sidecar, 512, 1 << 20, false);
+
+        bitmap.mark(512 * 3, 512 * 40).unwrap();
+        assert_eq!(bitmap.extents(0, 1 << 20, usize::MAX).unwrap(), [
+            (512 * 3, false), (512 * 40, true), ((1 << 20) - 512 * 43, false),
+        ]);
+    }
+
+    #[test]
+    fn survives_reopening_until_cleared() {
+        let sidecar = ScratchFile::new("reopen.dirty", b"");
+        open(&sidecar, 4096, 32 << 10, false).mark(0, 1).unwrap();
+
+        //  the saved granularity wins over the one asked for
+        let bitmap = open(&sidecar, 512, 32 << 10, false);
+        assert_eq!(bitmap.extents(0, 32 << 10, usize::MAX).unwrap(), [(4096, true), (28672, false)]);
+
+        bitmap.clear().unwrap();
+        drop(bitmap);
+        let bitmap = open(&sidecar, 4096, 32 << 10, false);
+        assert_eq!(bitmap.extents(0, 32 << 10, usize::MAX).unwrap(), [(32 << 10, false)]);
+
+        bitmap.mark(0, 1).unwrap();
+        drop(bitmap);
+        let bitmap = open(&sidecar, 4096, 32 << 10, true);
+        assert_eq!(bitmap.extents(0, 32 << 10, usize::MAX).unwrap(), [(32 << 10, false)]);
+    }
+
+    #[test]
+    fn growing_marks_the_new_space() {
+        let sidecar = ScratchFile::new("grow.dirty", b"");
+        let bitmap = open(&sidecar, 4096, 16 << 10, false);
+
+        bitmap.resize(16 << 10, 40 << 10).unwrap();
+        assert_eq!(bitmap.extents(0, 40 << 10, usize::MAX).unwrap(), [(16 << 10, false), (24 << 10, true)]);
+
+        //  shrinking forgets the cut off chunks, growing back marks them again
+        bitmap.resize(40 << 10, 8 << 10).unwrap();
+        bitmap.clear().unwrap();
+        bitmap.resize(8 << 10, 16 << 10).unwrap();
+        assert_eq!(bitmap.extents(0, 16 << 10, usize::MAX).unwrap(), [(8 << 10, false), (8 << 10, true)]);
+
+        //  a size change while the server was down counts the same
+        drop(bitmap);
+        let bitmap = open(&sidecar, 4096, 24 << 10, false);
+        assert_eq!(bitmap.extents(0, 24 << 10, usize::MAX).unwrap(), [(8 << 10, false), (16 << 10, true)]);
+    }
+
+    #[test]
+    fn rejects_bad_granularity() {
+        let sidecar = ScratchFile::new("granularity.dirty", b"");
+        assert!(DirtyBitmap::open("b".to_owned(), sidecar.path(), 1000, 1 << 20, false).is_err());
+        assert!(DirtyBitmap::open("b".to_owned(), sidecar.path(), 256, 1 << 20, false).is_err());
+    }
+}
@@ -0,0 +1,169 @@
+//  Exports config file, one section per export:
+//
+//      # answers to the empty export name
+//      default = disk0
+//
+//      [disk0]
+//      path = /srv/images/disk0.img
+//      read-write = true
+//      sparse-reads = false
+//      allow-psk-identity = alice     # may be repeated, like the other allow-* keys
+//      allow-cert = CN=alice,O=dev
+//      allow-ca-fingerprint = 48:c7:...
+//      display-name = Build farm root disk    # shown before the description, both default
+//      description = Debian 12, ext4          # to the user.nbd.name and .description xattrs
+//      max-payload = 1048576                  # largest read or write, in bytes
+//      dirty-bitmap = backup                  # qemu:dirty-bitmap:backup, clear-dirty-bitmap resets it
+//      bitmap-granularity = 65536
+//      bitmap-dir = /var/lib/nbd/bitmaps      # next to the image by default, required for block devices
+
+use std::fs::read_to_string;
+use std::io;
+
+use crate::export::ExportOptions;
+use crate::tls::normalize_fingerprint;
+
+pub struct ExportSpec {
+    pub name: String,
+    pub path: String,
+    pub options: ExportOptions,
+}
+
+pub struct Config {
+    pub default: Option<String>,
+    pub exports: Vec<ExportSpec>,
+}
+
+impl Config {
+    pub fn read(path: &str) -> io::Result<Self> {
+        Self::parse(&read_to_string(path)?)
+    }
+
+    fn parse(text: &str) -> io::Result<Self> {
+        let mut config = Config { default: None, exports: Vec::new() };
+        let mut section: Option<(String, Option<String>, ExportOptions)> = None;
+
+        for (n, line) in text.lines().enumerate() {
+            let invalid = |msg: &str| io::Error::new(
+                io::ErrorKind::InvalidData, format!("config line {}: {}", n + 1, msg)
+            );
+
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() { continue }
+
+            if line.starts_with('[') {
+                let name = line.strip_prefix('[').and_then(|l| l.strip_suffix(']'))
+                    .ok_or_else(|| invalid("unclosed section"))?;
+
+                if let Some(spec) = section.take() {
+                    config.exports.push(finish(spec)?);
+                }
+                section = Some((name.trim().to_owned(), None, ExportOptions::default()));
+                continue
+            }
+
+            let (key, value) = line.split_at(line.find('=').ok_or_else(|| invalid("expected key = value"))?);
+            let (key, value) = (key.trim(), value[1..].trim().to_owned());
+
+            let (_, path, options) = match section.as_mut() {
+                Some(section) => section,
+                None if key == "default" => {
+                    config.default = Some(value);
+                    continue
+                },
+                None => return Err(invalid("unknown global key"))
+            };
+
+            let flag = |value: &str| match value {
+                "true" | "yes" => Ok(true),
+                "false" | "no" => Ok(false),
+                _ => Err(invalid("expected true or false"))
+            };
+
+            match key {
+                "path" => *path = Some(value),
+                "read-write" => options.read_only = !flag(&value)?,
+                "sparse-reads" => options.sparse_reads = flag(&value)?,
+                "allow-psk-identity" => options.access.psk_identities.push(value),
+                "allow-cert" => options.access.cert_names.push(value),
+                "allow-ca-fingerprint" => options.access.ca_fingerprints.push(normalize_fingerprint(&value)),
+                "display-name" => options.display_name = Some(value),
+                "description" => options.description = Some(value),
+                "max-payload" => options.max_payload = value.parse().map_err(|_| invalid("bad payload size"))?,
+                "dirty-bitmap" => options.dirty_bitmaps.push(value),
+                "clear-dirty-bitmap" => options.cleared_bitmaps.push(value),
+                "bitmap-granularity" => options.bitmap_granularity = value.parse().map_err(|_| invalid("bad granularity"))?,
+                "bitmap-dir" => options.bitmap_dir = Some(value),
+                _ => return Err(invalid("unknown export key"))
+            }
+        }
+
+        if let Some(spec) = section {
+            config.exports.push(finish(spec)?);
+        }
+
+        Ok(config)
+    }
+}
+
+fn finish((name, path, options): (String, Option<String>, ExportOptions)) -> io::Result<ExportSpec> {
+    let path = path.ok_or_else(|| io::Error::new(
+        io::ErrorKind::InvalidData, format!("export {:?} has no path", name)
+    ))?;
+
+    Ok(ExportSpec { name, path, options })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sections_and_defaults() {
+        let config = Config::parse("
+            # comment
+            default = disk0
+
+            [disk0]
+            path = /srv/disk0.img   # trailing comment
+            read-write = yes
+            allow-ca-fingerprint = AB:cd:01
+            dirty-bitmap = backup
+            bitmap-granularity = 4096
+
+            [ disk1 ]
+            path = /srv/disk1.img
+            sparse-reads = false
+            display-name = Build farm root disk
+            max-payload = 65536
+        ").unwrap();
+
+        assert_eq!(config.default.as_deref(), Some("disk0"));
+        assert_eq!(config.exports.len(), 2);
+
+        let disk0 = &config.exports[0];
+        assert_eq!((disk0.name.as_str(), disk0.path.as_str()), ("disk0", "/srv/disk0.img"));
+        assert!(!disk0.options.read_only && disk0.options.sparse_reads);
+        assert_eq!(disk0.options.access.ca_fingerprints, ["abcd01"]);
+        assert_eq!(disk0.options.dirty_bitmaps, ["backup"]);
+        assert_eq!(disk0.options.bitmap_granularity, 4096);
+
+        let disk1 = &config.exports[1];
+        assert_eq!(disk1.name, "disk1");
+        assert!(disk1.options.read_only && !disk1.options.sparse_reads);
+        assert_eq!(disk1.options.display_name.as_deref(), Some("Build farm root disk"));
+        assert_eq!(disk1.options.max_payload, 65536);
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        let error = |text: &str| Config::parse(text).err().map(|e| e.to_string());
+
+        assert_eq!(error("[disk0\npath = /a"), Some("config line 1: unclosed section".to_owned()));
+        assert_eq!(error("path = /a"), Some("config line 1: unknown global key".to_owned()));
+        assert_eq!(error("[disk0]\npath /a"), Some("config line 2: expected key = value".to_owned()));
+        assert_eq!(error("[disk0]\npath = /a\nfrob = 1"), Some("config line 3: unknown export key".to_owned()));
+        assert_eq!(error("[disk0]\npath = /a\nread-write = maybe"), Some("config line 3: expected true or false".to_owned()));
+        assert_eq!(error("[disk0]\nread-write = true"), Some("export \"disk0\" has no path".to_owned()));
+    }
+}
@@ -0,0 +1,58 @@
+//  Runtime commands on a unix socket, one per line, each answered with `ok` or `error: REASON`:
+//
+//      create-bitmap EXPORT NAME    # start tracking writes in a new, clean bitmap, kept across restarts
+//      clear-bitmap EXPORT NAME     # forget the changes recorded in it so far
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::thread;
+
+use crate::export::Exports;
+
+/// Listens on `path` and serves every control connection on a thread of its own
+pub fn spawn(path: &str, exports: Arc<Exports>) -> io::Result<()> {
+    let listener = UnixListener::bind(path)?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let exports = Arc::clone(&exports);
+            let result = stream.map(|stream| thread::spawn(move || {
+                if let Err(err) = serve(stream, &exports) {
+                    eprintln!("control: {}", err);
+                }
+            }));
+            if let Err(err) = result {
+                eprintln!("control: {}", err);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn serve(stream: UnixStream, exports: &Exports) -> io::Result<()> {
+    let mut replies = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        match run(&line?, exports) {
+            Ok(()) => writeln!(replies, "ok")?,
+            Err(err) => writeln!(replies, "error: {}", err)?,
+        }
+    }
+    Ok(())
+}
+
+fn run(line: &str, exports: &Exports) -> io::Result<()> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (command, name, bitmap) = match words[..] {
+        [command, name, bitmap] => (command, name, bitmap),
+        _ => return Err(invalid("expected COMMAND EXPORT BITMAP".to_owned()))
+    };
+    let export = exports.get(name).ok_or_else(|| invalid(format!("no export {:?}", name)))?;
+
+    match command {
+        "create-bitmap" => export.create_bitmap(bitmap),
+        "clear-bitmap" => export.clear_bitmap(bitmap),
+        _ => Err(invalid(format!("unknown command {:?}", command)))
+    }
+}
@@ -1,53 +1,583 @@
-use std::fs::{File, metadata};
+use std::fs::{File, OpenOptions, metadata};
 use std::os::unix::fs::*;
-use std::io::{SeekFrom, Seek, Read};
+use std::os::unix::io::AsRawFd;
+use std::io::{SeekFrom, Seek};
+use std::path::Path;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::bitmap::{DirtyBitmap, DEFAULT_GRANULARITY};
+use crate::tls::AccessPolicy;
+
+const BLKDISCARD: libc::c_ulong = 0x1277;  // _IO(0x12, 119)
+const BLKZEROOUT: libc::c_ulong = 0x127f;  // _IO(0x12, 127)
+const BLKSSZGET: libc::c_ulong = 0x1268;  // _IO(0x12, 104)
+const BLKPBSZGET: libc::c_ulong = 0x127b;  // _IO(0x12, 123)
+
+pub const DEFAULT_MAX_PAYLOAD: u32 = 32 << 20;
+
+const ZERO_BUF_SIZE: usize = 1 << 20;
+
+const XATTR_NAME: &str = "user.nbd.name";
+const XATTR_DESCRIPTION: &str = "user.nbd.description";
+
+#[derive(Clone, Debug)]
+pub struct ExportOptions {
+    pub read_only: bool,
+    pub sparse_reads: bool,
+    pub access: AccessPolicy,
+    pub display_name: Option<String>,
+    pub description: Option<String>,
+    pub max_payload: u32,
+    pub dirty_bitmaps: Vec<String>,
+    pub cleared_bitmaps: Vec<String>,  //  reset at startup, created if missing
+    pub bitmap_granularity: u64,
+    pub bitmap_dir: Option<String>,    //  sidecar files go next to the image otherwise
+}
+
+/// NBD_INFO_BLOCK_SIZE constraints
+#[derive(Clone, Copy, Debug)]
+pub struct BlockSize {
+    pub min: u32,        //  offsets and lengths are multiples of it
+    pub preferred: u32,
+    pub max: u32,        //  largest read or write payload
+}
+
+impl BlockSize {
+    pub fn is_aligned(&self, offset: u64, len: u64) -> bool {
+        offset.is_multiple_of(self.min as u64) && len.is_multiple_of(self.min as u64)
+    }
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self { 
+            read_only: true, 
+            sparse_reads: true, 
+            access: AccessPolicy::default(), 
+            display_name: None, 
+            description: None,
+            max_payload: DEFAULT_MAX_PAYLOAD,
+            dirty_bitmaps: Vec::new(),
+            cleared_bitmaps: Vec::new(),
+            bitmap_granularity: DEFAULT_GRANULARITY,
+            bitmap_dir: None,
+        }
+    }
+}
 
 pub struct Export {
     pub name: String,
-    file: Option<File>,
-    pub size: u64,
+    pub description: String,  //  "display name: description" when both are set
+    pub read_only: bool,
+    pub sparse_reads: bool,
+    pub access: AccessPolicy,
+    pub block_device: bool,
+    pub can_trim: bool,
+    pub block_size: BlockSize,
+    bitmaps: RwLock<Vec<Arc<DirtyBitmap>>>,
+    bitmap_granularity: u64,
+    bitmap_dir: Option<String>,
+    path: String,
+    file: File,
+    size: AtomicU64,
 }
 
 impl Export {
-    pub fn new(filename: String) -> std::io::Result<Self> {
-        let mtdt = metadata(&filename)?;
-        let size = if mtdt.file_type().is_block_device() {
-            File::open(&filename)?.seek(SeekFrom::End(0))?  // kinda hacky, but works
+    pub fn new(name: String, path: &str, options: &ExportOptions) -> std::io::Result<Self> {
+        let (read_only, sparse_reads) = (options.read_only, options.sparse_reads);
+
+        let mtdt = metadata(path)?;
+        let block_device = mtdt.file_type().is_block_device();
+        let size = if block_device {
+            File::open(path)?.seek(SeekFrom::End(0))?  // kinda hacky, but works
         } else { 
             mtdt.len() 
         };
 
         if size == 0 {
-            panic!("size of the export is 0");
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("export {:?} is empty", name)))
         }
 
-        Ok( Self { name: filename, file: None, size } )
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(path)?;
+        let can_trim = !read_only && probe_trim(&file, block_device, size);
+        let block_size = block_size(&file, block_device, options.max_payload);
+
+        //  the config wins over attributes set on the image; NBD_INFO_NAME has to match
+        //  the name clients list and select, so the display name leads the description
+        let display_name = options.display_name.clone()
+            .or_else(|| read_xattr(path, XATTR_NAME));
+        let description = options.description.clone()
+            .or_else(|| read_xattr(path, XATTR_DESCRIPTION));
+        let description = match (display_name, description) {
+            (Some(display_name), Some(description)) => format!("{}: {}", display_name, description),
+            (display_name, description) => display_name.or(description).unwrap_or_default(),
+        };
+
+        //  bitmaps created over the control socket are only on disk, they come back from there
+        let saved = if block_device && options.bitmap_dir.is_none() {
+            Vec::new()
+        } else {
+            saved_bitmaps(path, options.bitmap_dir.as_deref())?
+        };
+        let mut names: Vec<&String> = options.dirty_bitmaps.iter()
+            .chain(options.cleared_bitmaps.iter())
+            .chain(saved.iter())
+            .collect();
+        names.sort();
+        names.dedup();
+        let bitmaps = names.into_iter()
+            .map(|bitmap| {
+                let clear = options.cleared_bitmaps.contains(bitmap);
+                open_bitmap(path, options.bitmap_dir.as_deref(), bitmap, options.bitmap_granularity, size, clear).map(Arc::new)
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        Ok( Self { 
+            name, 
+            description,
+            read_only, 
+            sparse_reads, 
+            access: options.access.clone(),
+            block_device, 
+            can_trim, 
+            block_size,
+            bitmaps: RwLock::new(bitmaps),
+            bitmap_granularity: options.bitmap_granularity,
+            bitmap_dir: options.bitmap_dir.clone(),
+            path: path.to_owned(),
+            file, 
+            size: AtomicU64::new(size) 
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    /// Whether `offset..offset + len` lies within the export, ranges past `u64::MAX` never do
+    pub fn contains(&self, offset: u64, len: u64) -> bool {
+        offset.checked_add(len).is_some_and(|end| end <= self.size())
+    }
+
+    pub fn bitmap(&self, name: &str) -> Option<Arc<DirtyBitmap>> {
+        self.bitmaps.read().unwrap().iter().find(|bitmap| bitmap.name == name).cloned()
+    }
+
+    pub fn bitmap_names(&self) -> Vec<String> {
+        self.bitmaps.read().unwrap().iter().map(|bitmap| bitmap.name.clone()).collect()
     }
 
-    pub fn load(&mut self) -> std::io::Result<()> {
-        if !self.loaded() {
-            self.file = Some(File::open(&self.name)?);
+    /// Starts tracking writes in a new, clean bitmap. Sessions see it from their next
+    /// NBD_OPT_SET_META_CONTEXT on, and it is loaded again from its file on restart.
+    pub fn create_bitmap(&self, name: &str) -> std::io::Result<()> {
+        let mut bitmaps = self.bitmaps.write().unwrap();
+        if bitmaps.iter().any(|bitmap| bitmap.name == name) {
+            return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!("bitmap {:?} already exists", name)))
         }
+
+        let bitmap = open_bitmap(&self.path, self.bitmap_dir.as_deref(), name, self.bitmap_granularity, self.size(), true)?;
+        bitmaps.push(Arc::new(bitmap));
+        bitmaps.sort_by(|a, b| a.name.cmp(&b.name));
         Ok(())
     }
 
-    pub fn loaded(&self) -> bool {
-        self.file.is_some()
+    //  the write lock waits out changes that were marked but aren't written yet
+    #[allow(clippy::readonly_write_lock)]
+    pub fn clear_bitmap(&self, name: &str) -> std::io::Result<()> {
+        let bitmaps = self.bitmaps.write().unwrap();
+        match bitmaps.iter().find(|bitmap| bitmap.name == name) {
+            Some(bitmap) => bitmap.clear(),
+            None => Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("no bitmap {:?}", name)))
+        }
+    }
+
+    /// Truncates or extends the backing file, the new size is seen by every connection.
+    pub fn resize(&self, new_size: u64) -> std::io::Result<()> {
+        if self.read_only {
+            return Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+        }
+        if self.block_device {
+            return Err(std::io::Error::from(std::io::ErrorKind::Unsupported))
+        }
+        if new_size == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput))
+        }
+
+        let old_size = self.size();
+        let bitmaps = self.bitmaps.read().unwrap();
+        bitmaps.iter().try_for_each(|bitmap| bitmap.resize(old_size, new_size))?;
+        self.file.set_len(new_size)?;
+        self.size.store(new_size, Ordering::SeqCst);
+        Ok(())
     }
 
     pub fn read(&self, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
-        let mut buf = vec![0u8; len];  // !FIXME: can panic 
+        let mut buf = vec![0u8; len];  //  callers keep len within block_size.max
         self.read_into(&mut buf, offset, len)?;
         Ok(buf)
     }
     
     pub fn read_into(&self, buf: &mut [u8], offset: u64, len: usize) -> std::io::Result<()> {        
-        if !self.loaded() { panic!("export not loaded"); }
-
-        // self.file.as_ref().unwrap().seek(SeekFrom::Start(offset))?;
-        // let _ = self.file.as_ref().unwrap().read(&mut buf[..len])?;
-        let _ = self.file.as_ref().unwrap().read_at(&mut buf[..len], offset)?;
+        // self.file.seek(SeekFrom::Start(offset))?;
+        // let _ = self.file.read(&mut buf[..len])?;
+        let _ = self.file.read_at(&mut buf[..len], offset)?;
 
         Ok(())
     }
+
+    pub fn write(&self, buf: &[u8], offset: u64) -> std::io::Result<()> {
+        if self.read_only {
+            return Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+        }
+
+        let _marked = self.mark_dirty(offset, buf.len() as u64)?;
+        self.file.write_all_at(buf, offset)
+    }
+
+    //  before the change itself, so a failed write can only leave a false positive.
+    //  Callers hold on to the guard until the change is done, a bitmap can't be
+    //  cleared between the two.
+    fn mark_dirty(&self, offset: u64, len: u64) -> std::io::Result<RwLockReadGuard<'_, Vec<Arc<DirtyBitmap>>>> {
+        let bitmaps = self.bitmaps.read().unwrap();
+        bitmaps.iter().try_for_each(|bitmap| bitmap.mark(offset, len))?;
+        Ok(bitmaps)
+    }
+
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.bitmaps.read().unwrap().iter().try_for_each(|bitmap| bitmap.sync())?;
+        self.file.sync_data()
+    }
+
+    pub fn trim(&self, offset: u64, len: u64) -> std::io::Result<()> {
+        if self.read_only {
+            return Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+        }
+
+        let _marked = self.mark_dirty(offset, len)?;
+        let file = &self.file;
+        if self.block_device {
+            //  trim is only a hint, so the range can be shrunk to whole sectors
+            let (start, end) = ((offset + 511) & !511, (offset + len) & !511);
+            if start < end { discard(file, start, end - start) } else { Ok(()) }
+        } else {
+            punch_hole(file, offset, len)
+        }
+    }
+
+    pub fn cache(&self, offset: u64, len: u64) -> std::io::Result<()> {
+
+        let file = &self.file;
+        //  posix_fadvise returns the error number instead of setting errno
+        match unsafe { 
+            libc::posix_fadvise(
+                file.as_raw_fd(), 
+                offset as libc::off_t, 
+                len as libc::off_t, 
+                libc::POSIX_FADV_WILLNEED
+            ) 
+        } {
+            0 => Ok(()),
+            errno => Err(std::io::Error::from_raw_os_error(errno))
+        }
+    }
+
+    /// Allocation map of the range as `(length, is_hole)` extents, stopping early after
+    /// `max` of them. Backends that can't report holes are described as fully allocated.
+    pub fn extents(&self, offset: u64, len: u64, max: usize) -> std::io::Result<Vec<(u64, bool)>> {
+
+        let file = &self.file;
+        let end = range_end(offset, len)?;
+        if self.block_device {
+            return Ok(vec![(len, false)])
+        }
+
+        let mut extents = Vec::new();
+        let mut pos = offset;
+        while pos < end && extents.len() < max {
+            let data = match seek(file, pos, libc::SEEK_DATA) {
+                Ok(data) => data.min(end),
+                Err(e) if e.raw_os_error() == Some(libc::ENXIO) => end,  // only hole till EOF
+                Err(_) => { extents.push((end - pos, false)); break }
+            };
+
+            if data > pos {
+                extents.push((data - pos, true));
+                pos = data;
+            } else {
+                let hole = seek(file, pos, libc::SEEK_HOLE)?.min(end);
+                extents.push((hole - pos, false));
+                pos = hole;
+            }
+        }
+
+        Ok(extents)
+    }
+
+    /// Zeroes the range, deallocating it unless `no_hole` is set. With `fast` set
+    /// the call fails with `ErrorKind::Unsupported` instead of writing zero buffers.
+    pub fn write_zeroes(&self, offset: u64, len: u64, no_hole: bool, fast: bool) -> std::io::Result<()> {
+        if self.read_only {
+            return Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+        }
+
+        let _marked = self.mark_dirty(offset, len)?;
+        let file = &self.file;
+        //  on block devices punch hole is done without fallback to writing zeroes
+        if !no_hole && punch_hole(file, offset, len).is_ok() {
+            return Ok(())
+        }
+
+        if self.block_device {
+            //  BLKZEROOUT may silently fall back to writing zeroes itself
+            if fast { return Err(std::io::Error::from(std::io::ErrorKind::Unsupported)) }
+
+            let (start, end) = ((offset + 511) & !511, (offset + len) & !511);
+            if start < end {
+                zero_out(file, start, end - start)?;
+                write_zero_buffers(file, offset, start - offset)?;
+                write_zero_buffers(file, end, offset + len - end)
+            } else {
+                write_zero_buffers(file, offset, len)
+            }
+        } else if zero_range(file, offset, len).is_ok() {
+            Ok(())
+        } else if fast {
+            Err(std::io::Error::from(std::io::ErrorKind::Unsupported))
+        } else {
+            write_zero_buffers(file, offset, len)
+        }
+    }
+}
+
+/// Every export of the server. The default one answers to the empty name.
+pub struct Exports {
+    exports: Vec<Arc<Export>>,
+    default: Option<Arc<Export>>,
+}
+
+impl Exports {
+    pub fn new(exports: Vec<Export>, default: Option<&str>) -> std::io::Result<Self> {
+        let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+
+        let exports: Vec<Arc<Export>> = exports.into_iter().map(Arc::new).collect();
+        for (i, export) in exports.iter().enumerate() {
+            if export.name.is_empty() {
+                return Err(invalid("export name can't be empty".to_owned()))
+            }
+            if exports[..i].iter().any(|other| other.name == export.name) {
+                return Err(invalid(format!("duplicate export {:?}", export.name)))
+            }
+        }
+
+        let default = match default {
+            Some(name) => Some(exports.iter().find(|e| e.name == name).cloned()
+                .ok_or_else(|| invalid(format!("unknown default export {:?}", name)))?),
+            None if exports.len() == 1 => exports.first().cloned(),
+            None => None
+        };
+
+        Ok(Self { exports, default })
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Export>> {
+        if name.is_empty() {
+            self.default()
+        } else {
+            self.exports.iter().find(|e| e.name == name).cloned()
+        }
+    }
+
+    pub fn default(&self) -> Option<Arc<Export>> {
+        self.default.clone()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Export>> {
+        self.exports.iter()
+    }
+}
+
+fn open_bitmap(path: &str, dir: Option<&str>, name: &str, granularity: u64, size: u64, clear: bool) -> std::io::Result<DirtyBitmap> {
+    if name.is_empty() || name.contains('/') {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("bad bitmap name {:?}", name)))
+    }
+
+    let sidecar = match dir {
+        Some(dir) => format!("{}/{}.{}.dirty", dir, Path::new(path).file_name().unwrap_or_default().to_string_lossy(), name),
+        //  next to a block device would be devtmpfs, gone on the next boot
+        None if metadata(path)?.file_type().is_block_device() => return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput, format!("{} is a block device, its dirty bitmaps need a bitmap-dir", path)
+        )),
+        None => format!("{}.{}.dirty", path, name)
+    };
+    DirtyBitmap::open(name.to_owned(), &sidecar, granularity, size, clear)
+}
+
+//  names of the bitmaps with a sidecar file for the image, where `open_bitmap` puts them
+fn saved_bitmaps(path: &str, dir: Option<&str>) -> std::io::Result<Vec<String>> {
+    let image = Path::new(path);
+    let dir = match dir {
+        Some(dir) => Path::new(dir),
+        None => image.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new(".")),
+    };
+    let prefix = format!("{}.", image.file_name().unwrap_or_default().to_string_lossy());
+
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err)
+    };
+
+    let mut names = Vec::new();
+    for entry in entries {
+        let file_name = entry?.file_name();
+        let name = file_name.to_string_lossy().strip_prefix(&prefix)
+            .and_then(|name| name.strip_suffix(".dirty"))
+            .filter(|name| !name.is_empty())
+            .map(str::to_owned);
+        names.extend(name);
+    }
+    Ok(names)
+}
+
+/// End of `offset..offset + len`, an error if it doesn't fit in 64 bits
+pub fn range_end(offset: u64, len: u64) -> std::io::Result<u64> {
+    offset.checked_add(len).ok_or_else(|| std::io::Error::from(std::io::ErrorKind::InvalidInput))
+}
+
+fn cvt(ret: libc::c_int) -> std::io::Result<()> {
+    if ret < 0 { Err(std::io::Error::last_os_error()) } else { Ok(()) }
+}
+
+fn seek(file: &File, offset: u64, whence: libc::c_int) -> std::io::Result<u64> {
+    let ret = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+    if ret < 0 { Err(std::io::Error::last_os_error()) } else { Ok(ret as u64) }
+}
+
+fn punch_hole(file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+    cvt(unsafe {
+        libc::fallocate(
+            file.as_raw_fd(), 
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE, 
+            offset as libc::off_t, 
+            len as libc::off_t
+        )
+    })
+}
+
+fn zero_range(file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+    cvt(unsafe {
+        libc::fallocate(
+            file.as_raw_fd(), 
+            libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE, 
+            offset as libc::off_t, 
+            len as libc::off_t
+        )
+    })
+}
+
+fn zero_out(file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+    let range: [u64; 2] = [offset, len];
+    cvt(unsafe { libc::ioctl(file.as_raw_fd(), BLKZEROOUT, &range) })
+}
+
+fn write_zero_buffers(file: &File, mut offset: u64, len: u64) -> std::io::Result<()> {
+    let zeroes = vec![0u8; ZERO_BUF_SIZE.min(len as usize)];
+    let end = range_end(offset, len)?;
+
+    while offset < end {
+        let n = zeroes.len().min((end - offset) as usize);
+        file.write_all_at(&zeroes[..n], offset)?;
+        offset += n as u64;
+    }
+
+    Ok(())
+}
+
+fn discard(file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+    let range: [u64; 2] = [offset, len];
+    cvt(unsafe { libc::ioctl(file.as_raw_fd(), BLKDISCARD, &range) })
+}
+
+//  Both calls are no-ops on these ranges, but fail if the backend can't deallocate at all
+fn probe_trim(file: &File, block_device: bool, size: u64) -> bool {
+    if block_device {
+        discard(file, 0, 0).is_ok()
+    } else {
+        punch_hole(file, size, 1).is_ok()
+    }
+}
+
+//  Block devices can only be accessed in logical sectors, files in any unit.
+//  The payload cap is kept a multiple of the minimum and at least the preferred size.
+fn block_size(file: &File, block_device: bool, max_payload: u32) -> BlockSize {
+    let (min, preferred) = if block_device {
+        let logical = sector_size(file, BLKSSZGET).unwrap_or(512);
+        let physical = sector_size(file, BLKPBSZGET).unwrap_or(logical);
+        (logical, physical.max(4096))
+    } else {
+        (1, 4096)
+    };
+
+    BlockSize { min, preferred, max: max_payload.max(preferred) / min * min }
+}
+
+fn sector_size(file: &File, request: libc::c_ulong) -> Option<u32> {
+    let mut size: libc::c_uint = 0;
+    cvt(unsafe { libc::ioctl(file.as_raw_fd(), request, &mut size) }).ok()?;
+    if size.is_power_of_two() { Some(size) } else { None }
+}
+
+//  missing or unreadable attributes are treated alike
+fn read_xattr(path: &str, name: &str) -> Option<String> {
+    let path = std::ffi::CString::new(path).ok()?;
+    let name = std::ffi::CString::new(name).ok()?;
+
+    let len = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+    if len <= 0 { return None }
+
+    let mut buf = vec![0u8; len as usize];
+    let len = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+    if len < 0 { return None }
+    buf.truncate(len as usize);
+
+    String::from_utf8(buf).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchFile;
+
+    #[test]
+    fn rejects_empty_images() {
+        let image = ScratchFile::new("empty.img", b"");
+        let error = Export::new("disk".to_owned(), image.path(), &ExportOptions::default()).err().unwrap();
+
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(error.to_string(), "export \"disk\" is empty");
+    }
+
+    #[test]
+    fn created_bitmaps_survive_a_restart() {
+        let image = ScratchFile::new("restart.img", &vec![0; 1 << 20]);
+        let options = ExportOptions { read_only: false, ..ExportOptions::default() };
+
+        let export = Export::new("disk".to_owned(), image.path(), &options).unwrap();
+        assert!(export.bitmap_names().is_empty());
+        export.create_bitmap("nightly").unwrap();
+        export.write(&[1; 512], 128 << 10).unwrap();
+        drop(export);
+
+        let export = Export::new("disk".to_owned(), image.path(), &options).unwrap();
+        assert_eq!(export.bitmap_names(), ["nightly"]);
+        assert_eq!(export.bitmap("nightly").unwrap().extents(0, 1 << 20, usize::MAX).unwrap(), [
+            (128 << 10, false), (64 << 10, true), (832 << 10, false),
+        ]);
+
+        std::fs::remove_file(format!("{}.nightly.dirty", image.path())).unwrap();
+    }
 }
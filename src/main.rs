@@ -2,43 +2,479 @@ extern crate num_derive;
 extern crate num_traits;
 
 mod server;
+mod async_server;
+mod session;
+mod transport;
 mod protocol;
 mod export;
+mod meta_context;
+mod tls;
+mod config;
+mod bitmap;
+mod control;
+#[cfg(test)]
+mod testing;
 
-use std::net::{TcpListener};
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
 
 use clap::{Arg, App};
 
+use async_server::AsyncServer;
 use server::{Server, ServerError};
+use bitmap::DEFAULT_GRANULARITY;
+use config::{Config, ExportSpec};
+use export::{Export, ExportOptions, Exports, DEFAULT_MAX_PAYLOAD};
+use tls::{AccessPolicy, Tls, TlsMode, normalize_fingerprint};
+use transport::{AsyncTransport, Stdio, Transport};
 
 fn main() -> Result<(), ServerError> {
     let matches = App::new("NBD Server")
         .arg(Arg::with_name("input file")
-            .required(true)
+            .required_unless_one(&["export", "config"])
             .takes_value(true)
-            .about("path to export")
+            .about("path to export, named by the path and used as the default export")
         ).arg(Arg::with_name("chunk size")
             .takes_value(true)
             .default_value("4096")
             .about("payload maximum size for chunk of structured reply")
+        ).arg(Arg::with_name("read-write")
+            .long("read-write")
+            .short('w')
+            .about("allow clients to write to the exports given on the command line")
+        ).arg(Arg::with_name("no-sparse-reads")
+            .long("no-sparse-reads")
+            .about("always send data chunks in structured reads, even for holes")
+        ).arg(Arg::with_name("max-payload")
+            .long("max-payload")
+            .takes_value(true)
+            .about("largest read or write a client may send to the command line exports, in bytes [default: 33554432]")
+        ).arg(Arg::with_name("dirty-bitmap")
+            .long("dirty-bitmap")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .about("track writes to the command line exports in a qemu:dirty-bitmap:NAME context, may be repeated")
+        ).arg(Arg::with_name("clear-dirty-bitmap")
+            .long("clear-dirty-bitmap")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .about("like --dirty-bitmap, but starts the bitmap clean, may be repeated")
+        ).arg(Arg::with_name("bitmap-granularity")
+            .long("bitmap-granularity")
+            .takes_value(true)
+            .about("bytes covered by one bit of new dirty bitmaps [default: 65536]")
+        ).arg(Arg::with_name("bitmap-dir")
+            .long("bitmap-dir")
+            .takes_value(true)
+            .about("directory for dirty bitmap files, they are kept next to the image otherwise, required for block devices")
+        ).arg(Arg::with_name("control")
+            .long("control")
+            .takes_value(true)
+            .about("unix socket taking create-bitmap and clear-bitmap commands, see README")
+        ).arg(Arg::with_name("export")
+            .long("export")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .about("export a file as NAME=PATH, may be repeated")
+        ).arg(Arg::with_name("config")
+            .long("config")
+            .takes_value(true)
+            .about("file with a [name] section per export, see README")
+        ).arg(Arg::with_name("default-export")
+            .long("default-export")
+            .takes_value(true)
+            .about("export served to clients asking for the empty name")
+        ).arg(Arg::with_name("oldstyle-port")
+            .long("oldstyle-port")
+            .takes_value(true)
+            .about("also serve the default export to oldstyle clients on this port")
+        ).arg(Arg::with_name("max-sessions")
+            .long("max-sessions")
+            .takes_value(true)
+            .about("connections served at once, more are refused [default: unlimited]")
+        ).arg(Arg::with_name("async")
+            .long("async")
+            .about("serve connections on a tokio runtime instead of a thread each")
+        ).arg(Arg::with_name("unix")
+            .long("unix")
+            .takes_value(true)
+            .about("listen on a unix socket at this path instead of 127.0.0.1:10809")
+        ).arg(Arg::with_name("stdio")
+            .long("stdio")
+            .conflicts_with_all(&["unix", "oldstyle-port", "async"])
+            .about("serve one newstyle client on stdin and stdout, as under inetd, then exit")
+        ).arg(Arg::with_name("tls")
+            .long("tls")
+            .takes_value(true)
+            .possible_values(&["off", "optional", "required"])
+            .default_value("off")
+            .about("whether clients may or must upgrade with NBD_OPT_STARTTLS")
+        ).arg(Arg::with_name("tls-cert")
+            .long("tls-cert")
+            .takes_value(true)
+            .conflicts_with("tls-psk")
+            .about("PEM certificate chain for tls")
+        ).arg(Arg::with_name("tls-key")
+            .long("tls-key")
+            .takes_value(true)
+            .conflicts_with("tls-psk")
+            .about("PEM private key for tls")
+        ).arg(Arg::with_name("tls-client-ca")
+            .long("tls-client-ca")
+            .takes_value(true)
+            .conflicts_with("tls-psk")
+            .about("PEM bundle of CAs to verify client certificates against")
+        ).arg(Arg::with_name("tls-psk")
+            .long("tls-psk")
+            .takes_value(true)
+            .about("keys file of identity:hex-key lines to use TLS-PSK instead of a certificate, needs the psk cargo feature")
+        ).arg(Arg::with_name("allow-psk-identity")
+            .long("allow-psk-identity")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .about("restrict command line exports to clients with this PSK identity, may be repeated")
+        ).arg(Arg::with_name("allow-cert")
+            .long("allow-cert")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .about("restrict command line exports to client certificates with this subject (CN=alice,O=dev) or SAN, may be repeated")
+        ).arg(Arg::with_name("allow-ca-fingerprint")
+            .long("allow-ca-fingerprint")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .about("restrict command line exports to client certificates issued by the CA with this SHA-256 fingerprint, may be repeated")
         )
         .get_matches();
 
-    let filename = matches.value_of("input file").unwrap();
     let chunk_size = matches.value_of("chunk size")
         .map(str::parse::<u32>)
         .unwrap()
         .expect("bad chunk size");
+    let options = ExportOptions {
+        read_only: !matches.is_present("read-write"),
+        sparse_reads: !matches.is_present("no-sparse-reads"),
+        access: AccessPolicy {
+            psk_identities: values(&matches, "allow-psk-identity"),
+            cert_names: values(&matches, "allow-cert"),
+            ca_fingerprints: values(&matches, "allow-ca-fingerprint").iter()
+                .map(|fp| normalize_fingerprint(fp))
+                .collect(),
+        },
+        max_payload: matches.value_of("max-payload")
+            .map(|size| size.parse().expect("bad max payload"))
+            .unwrap_or(DEFAULT_MAX_PAYLOAD),
+        dirty_bitmaps: values(&matches, "dirty-bitmap"),
+        cleared_bitmaps: values(&matches, "clear-dirty-bitmap"),
+        bitmap_granularity: matches.value_of("bitmap-granularity")
+            .map(|size| size.parse().expect("bad bitmap granularity"))
+            .unwrap_or(DEFAULT_GRANULARITY),
+        bitmap_dir: matches.value_of("bitmap-dir").map(str::to_owned),
+        ..ExportOptions::default()
+    };
+
+    let tls_mode: TlsMode = matches.value_of("tls").unwrap().parse().unwrap();
+    let tls = match tls_mode {
+        TlsMode::Off => Tls::off(),
+        mode => match matches.value_of("tls-psk") {
+            Some(keys) => Tls::with_psk(mode, keys)?,
+            None => Tls::with_certificate(
+                mode, 
+                matches.value_of("tls-cert").expect("--tls-cert or --tls-psk is required for tls"), 
+                matches.value_of("tls-key").expect("--tls-key is required for tls"),
+                matches.value_of("tls-client-ca")
+            )?
+        }
+    };
 
-    let listener = TcpListener::bind("127.0.0.1:10809")?;
+    let mut specs = Vec::new();
+    let mut default = matches.value_of("default-export").map(str::to_owned);
+
+    if let Some(path) = matches.value_of("config") {
+        let config = Config::read(path)?;
+        default = default.or(config.default);
+        specs.extend(config.exports);
+    }
+    for export in values(&matches, "export") {
+        let (name, path) = export.split_at(export.find('=').expect("exports are given as NAME=PATH"));
+        specs.push(ExportSpec { name: name.to_owned(), path: path[1..].to_owned(), options: options.clone() });
+    }
+    if let Some(path) = matches.value_of("input file") {
+        default = default.or_else(|| Some(path.to_owned()));
+        specs.push(ExportSpec { name: path.to_owned(), path: path.to_owned(), options: options.clone() });
+    }
+
+    for spec in &specs {
+        tls.check_policy(&spec.name, &spec.options.access)?;
+    }
+
+    let exports = specs.iter()
+        .map(|spec| Export::new(spec.name.clone(), &spec.path, &spec.options))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let exports = Arc::new(Exports::new(exports, default.as_deref())?);
+
+    let shared = Shared {
+        exports,
+        chunk_size,
+        tls,
+        sessions: Sessions {
+            active: Arc::new(AtomicUsize::new(0)),
+            max: matches.value_of("max-sessions").map(|n| n.parse().expect("bad max sessions")),
+        },
+    };
+
+    if let Some(path) = matches.value_of("control") {
+        control::spawn(path, Arc::clone(&shared.exports))?;
+    }
+
+    //  inetd style, the one client is already connected on stdin and stdout
+    if matches.is_present("stdio") {
+        return run(Stdio, Negotiation::Newstyle, shared)
+    }
+
+    let mut listeners = vec![match matches.value_of("unix") {
+        Some(path) => (Listener::Unix(UnixListener::bind(path)?, path.to_owned()), Negotiation::Newstyle),
+        None => (Listener::Tcp(TcpListener::bind("127.0.0.1:10809")?), Negotiation::Newstyle),
+    }];
+    if let Some(port) = matches.value_of("oldstyle-port") {
+        let invalid = |msg: &str| Err(io::Error::new(io::ErrorKind::InvalidInput, msg).into());
+        if shared.exports.default().is_none() {
+            return invalid("oldstyle clients need a default export")
+        }
+        //  oldstyle has no STARTTLS, the export would go out in plaintext
+        if shared.tls.mode == TlsMode::Required {
+            return invalid("oldstyle clients can't use tls, --tls required rules out --oldstyle-port")
+        }
+        let port = port.parse::<u16>().expect("bad oldstyle port");
+        listeners.push((Listener::Tcp(TcpListener::bind(("127.0.0.1", port))?), Negotiation::Oldstyle));
+    }
+
+    if matches.is_present("async") {
+        let runtime = tokio::runtime::Runtime::new()?;
+        return runtime.block_on(serve_async(listeners, shared))
+    }
     
-    for stream in listener.incoming() {
-        match Server::handshake(filename, stream?, chunk_size)?.option_haggle() {
-            Ok(mut server) => server.serve()?,
-            Err(ServerError::Abort) => eprintln!("client aborted"),
-            Err(err) => return Err(err)
+    loop {
+        let (connection, peer, negotiation) = match accept(&listeners) {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                thread::sleep(accept_failed(&err));
+                continue
+            }
         };
+
+        //  over the limit the connection is dropped before the greeting
+        let slot = match shared.sessions.claim() {
+            Some(slot) => slot,
+            None => {
+                eprintln!("{}: too many sessions, refusing", peer);
+                continue
+            }
+        };
+
+        let shared = shared.clone();
+        thread::spawn(move || {
+            let _slot = slot;
+            let result = match connection {
+                Connection::Tcp(stream) => run(stream, negotiation, shared),
+                Connection::Unix(stream) => run(stream, negotiation, shared),
+            };
+
+            report(&peer, result);
+        });
+    }
+}
+
+fn run<T: Transport>(stream: T, negotiation: Negotiation, shared: Shared) -> Result<(), ServerError> {
+    match negotiation {
+        Negotiation::Newstyle => Server::handshake(shared.exports, stream, shared.chunk_size, shared.tls)
+            .and_then(Server::option_haggle),
+        Negotiation::Oldstyle => Server::oldstyle(shared.exports, stream, shared.chunk_size),
+    }.and_then(Server::serve)
+}
+
+//  one accept loop per listener, sessions are tasks instead of threads
+async fn serve_async(listeners: Vec<(Listener, Negotiation)>, shared: Shared) -> Result<(), ServerError> {
+    let mut accepting = tokio::task::JoinSet::<()>::new();
+
+    for (listener, negotiation) in listeners {
+        let shared = shared.clone();
+
+        match listener {
+            Listener::Tcp(listener) => {
+                listener.set_nonblocking(true)?;
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                accepting.spawn(async move {
+                    loop {
+                        match listener.accept().await {
+                            Ok((stream, addr)) => spawn_async(stream, addr.to_string(), negotiation, &shared),
+                            Err(err) => tokio::time::sleep(accept_failed(&err)).await,
+                        }
+                    }
+                });
+            },
+            Listener::Unix(listener, path) => {
+                listener.set_nonblocking(true)?;
+                let listener = tokio::net::UnixListener::from_std(listener)?;
+                accepting.spawn(async move {
+                    loop {
+                        match listener.accept().await {
+                            Ok((stream, _)) => spawn_async(stream, path.clone(), negotiation, &shared),
+                            Err(err) => tokio::time::sleep(accept_failed(&err)).await,
+                        }
+                    }
+                });
+            },
+        }
     }
 
+    //  the accept loops never return, one that panics stops its own listener only
+    while let Some(result) = accepting.join_next().await {
+        if let Err(err) = result {
+            eprintln!("accept loop failed: {}", err);
+        }
+    }
     Ok(())
 }
+
+fn spawn_async<T: AsyncTransport>(stream: T, peer: String, negotiation: Negotiation, shared: &Shared) {
+    let slot = match shared.sessions.claim() {
+        Some(slot) => slot,
+        None => {
+            eprintln!("{}: too many sessions, refusing", peer);
+            return
+        }
+    };
+
+    let shared = shared.clone();
+    tokio::spawn(async move {
+        let _slot = slot;
+        report(&peer, run_async(stream, negotiation, shared).await);
+    });
+}
+
+async fn run_async<T: AsyncTransport>(stream: T, negotiation: Negotiation, shared: Shared) -> Result<(), ServerError> {
+    let server = match negotiation {
+        Negotiation::Newstyle => AsyncServer::handshake(shared.exports, stream, shared.chunk_size, shared.tls).await?
+            .option_haggle().await?,
+        Negotiation::Oldstyle => AsyncServer::oldstyle(shared.exports, stream, shared.chunk_size).await?,
+    };
+    server.serve().await
+}
+
+//  errors only end the session they happened in
+fn report(peer: &str, result: Result<(), ServerError>) {
+    match result {
+        Ok(()) => {},
+        Err(ServerError::Abort) => eprintln!("{}: client aborted", peer),
+        Err(err) => eprintln!("{}: {}", peer, err),
+    }
+}
+
+//  what every session gets a copy of
+#[derive(Clone)]
+struct Shared {
+    exports: Arc<Exports>,
+    chunk_size: u32,
+    tls: Tls,
+    sessions: Sessions,
+}
+
+#[derive(Clone)]
+struct Sessions {
+    active: Arc<AtomicUsize>,
+    max: Option<usize>,
+}
+
+impl Sessions {
+    //  a place in the session count, `None` if all of them are taken
+    fn claim(&self) -> Option<SessionSlot> {
+        let active = self.active.fetch_add(1, Ordering::SeqCst);
+        let slot = SessionSlot(Arc::clone(&self.active));
+        if self.max.is_some_and(|max| active >= max) {
+            return None
+        }
+        Some(slot)
+    }
+}
+
+//  frees its place in the session count however the session ends
+struct SessionSlot(Arc<AtomicUsize>);
+
+impl Drop for SessionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Negotiation {
+    Newstyle,
+    Oldstyle,
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, String),  //  socket path, unix peers have no address of their own
+}
+
+enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+//  waits for a connection on any of the listeners, returns it with a name for the logs
+fn accept(listeners: &[(Listener, Negotiation)]) -> io::Result<(Connection, String, Negotiation)> {
+    let mut fds: Vec<libc::pollfd> = listeners.iter()
+        .map(|(listener, _)| {
+            let fd = match listener {
+                Listener::Tcp(l) => l.as_raw_fd(),
+                Listener::Unix(l, _) => l.as_raw_fd(),
+            };
+            libc::pollfd { fd, events: libc::POLLIN, revents: 0 }
+        })
+        .collect();
+
+    loop {
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted { continue }
+            return Err(err)
+        }
+
+        if let Some(i) = fds.iter().position(|fd| fd.revents != 0) {
+            let (listener, negotiation) = &listeners[i];
+            return match listener {
+                Listener::Tcp(l) => l.accept().map(|(stream, addr)| (Connection::Tcp(stream), addr.to_string(), *negotiation)),
+                Listener::Unix(l, path) => l.accept().map(|(stream, _)| (Connection::Unix(stream), path.clone(), *negotiation)),
+            }
+        }
+    }
+}
+
+//  a failed accept is logged and the listener kept, out of descriptors it
+//  waits a moment for sessions to end instead of spinning on the error
+fn accept_failed(err: &io::Error) -> Duration {
+    eprintln!("accept: {}", err);
+    match err.raw_os_error() {
+        Some(libc::EMFILE) | Some(libc::ENFILE) => Duration::from_millis(100),
+        _ => Duration::ZERO,
+    }
+}
+
+fn values(matches: &clap::ArgMatches, name: &str) -> Vec<String> {
+    matches.values_of(name)
+        .map(|values| values.map(str::to_owned).collect())
+        .unwrap_or_default()
+}
@@ -0,0 +1,97 @@
+use crate::export::Export;
+
+pub const BASE_ALLOCATION: &str = "base:allocation";
+pub const DIRTY_BITMAP: &str = "qemu:dirty-bitmap:";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetaContext {
+    BaseAllocation,
+    DirtyBitmap(String),  //  name of one of the export's bitmaps
+}
+
+impl MetaContext {
+    pub fn name(&self) -> String {
+        match self {
+            MetaContext::BaseAllocation => BASE_ALLOCATION.to_owned(),
+            MetaContext::DirtyBitmap(bitmap) => format!("{}{}", DIRTY_BITMAP, bitmap),
+        }
+    }
+}
+
+/// Metadata contexts that a client can select for an export
+pub struct Registry {
+    contexts: Vec<MetaContext>,
+}
+
+impl Registry {
+    pub fn new(export: &Export) -> Self {
+        let bitmaps = export.bitmap_names().into_iter().map(MetaContext::DirtyBitmap);
+        Self { contexts: std::iter::once(MetaContext::BaseAllocation).chain(bitmaps).collect() }
+    }
+
+    /// Contexts for `NBD_OPT_LIST_META_CONTEXT`: no queries list everything,
+    /// and a bare namespace (`base:`) or `qemu:dirty-bitmap:` works as a wildcard.
+    pub fn list(&self, queries: &[String]) -> Vec<MetaContext> {
+        if queries.is_empty() {
+            return self.contexts.clone()
+        }
+
+        self.contexts.iter()
+            .filter(|ctx| queries.iter().any(|q| *q == ctx.name() || (q.ends_with(':') && ctx.name().starts_with(q.as_str()))))
+            .cloned()
+            .collect()
+    }
+
+    /// Contexts for `NBD_OPT_SET_META_CONTEXT`: only exact names select anything,
+    /// unknown names and wildcards are ignored.
+    pub fn select(&self, queries: &[String]) -> Vec<MetaContext> {
+        self.contexts.iter()
+            .filter(|ctx| queries.iter().any(|q| *q == ctx.name()))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> Registry {
+        Registry { contexts: vec![
+            MetaContext::BaseAllocation,
+            MetaContext::DirtyBitmap("backup".to_owned()),
+            MetaContext::DirtyBitmap("nightly".to_owned()),
+        ] }
+    }
+
+    fn queries(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn list_matches_names_and_namespaces() {
+        let registry = registry();
+        let bitmaps = vec![MetaContext::DirtyBitmap("backup".to_owned()), MetaContext::DirtyBitmap("nightly".to_owned())];
+
+        assert_eq!(registry.list(&[]), registry.contexts);
+        assert_eq!(registry.list(&queries(&["base:"])), [MetaContext::BaseAllocation]);
+        assert_eq!(registry.list(&queries(&["qemu:dirty-bitmap:"])), bitmaps);
+        assert_eq!(registry.list(&queries(&["qemu:dirty-bitmap:nightly", "base:allocation"])), [
+            MetaContext::BaseAllocation,
+            MetaContext::DirtyBitmap("nightly".to_owned()),
+        ]);
+        assert!(registry.list(&queries(&["qemu:dirty-bitmap:weekly", "x-other:"])).is_empty());
+    }
+
+    #[test]
+    fn select_takes_exact_names_only() {
+        let registry = registry();
+
+        assert!(registry.select(&[]).is_empty());
+        assert!(registry.select(&queries(&["base:", "qemu:dirty-bitmap:"])).is_empty());
+        assert_eq!(registry.select(&queries(&["qemu:dirty-bitmap:backup", "base:allocation", "unknown:thing"])), [
+            MetaContext::BaseAllocation,
+            MetaContext::DirtyBitmap("backup".to_owned()),
+        ]);
+    }
+}
@@ -12,6 +12,8 @@ pub fn send_msg<W, M>(stream: &mut W, msg: M) -> io::Result<()>
           M: Message
 {
     stream.write_all(msg.get_header())?;
-    msg.get_data().map(|buf| stream.write_all(buf).unwrap());
+    if let Some(buf) = msg.get_data() {
+        stream.write_all(buf)?;
+    }
     stream.flush()
 }
\ No newline at end of file
@@ -9,16 +9,17 @@ const OPTION_REPLY_MAGIC: u64 = 0x3e889045565a9;
 
 #[derive(Debug)]
 pub enum NbdOption {
-    ExportName,
+    ExportName(Option<String>),
     Abort,
     List,
     PeekExport,
-    Starttls,
+    Starttls(bool),  // same as StructuredReply
     Info(Option<String>, Option<Vec<Info>>),
     Go(Option<String>, Option<Vec<Info>>),
     StructuredReply(bool),  // boot whether to allow or not
     ListMetaContext(Option<String>, Option<Vec<String>>),
-    SetMetaContext,
+    SetMetaContext(Option<String>, Option<Vec<String>>),
+    ExtendedHeaders(bool),  // same as StructuredReply
 }
 
 #[derive(Debug)]
@@ -50,7 +51,9 @@ impl TryFrom<&[u8]> for NbdOption {
 
             Ok( match type_ {
                 // ExportName
-                1 => NbdOption::ExportName,
+                1 => NbdOption::ExportName(if data_len > 0 {
+                    Some( String::from_utf8(data[..data_len].into()).map_err(|_| OptionError::Parse)? )
+                } else { None }),
                 // Abort
                 2 => NbdOption::Abort,
                 // List
@@ -58,7 +61,7 @@ impl TryFrom<&[u8]> for NbdOption {
                 // PeekExport
                 4 => NbdOption::PeekExport,
                 // Starttls
-                5 => NbdOption::Starttls,
+                5 => NbdOption::Starttls(data_len == 0),
                 // Info & Go
                 6 | 7 => {
                     let (name, info_requests) = parse_info_go(data)?;
@@ -76,7 +79,13 @@ impl TryFrom<&[u8]> for NbdOption {
                     NbdOption::ListMetaContext(name, queries)
                 }, 
                 // SetMetaContext 
-                10 => NbdOption::SetMetaContext,
+                10 => {
+                    let (name, queries) = parse_list_meta_context(data)?;
+                    NbdOption::SetMetaContext(name, queries)
+                },
+
+                // ExtendedHeaders
+                11 => NbdOption::ExtendedHeaders(data_len == 0),
 
                 n => return Err(Self::Error::UnknownOption(n))
             })
@@ -85,18 +94,18 @@ impl TryFrom<&[u8]> for NbdOption {
 }
 
 fn parse_info_go(data: &[u8]) -> Result<(Option<String>, Option<Vec<Info>>), OptionError> {
-    let (name_len, data) = data.split_at(4);
+    let (name_len, data) = split(data, 4)?;
     let name_len: usize = name_len.try_into()
         .map(u32::from_be_bytes)
         .map_err(|_| OptionError::Parse)? as usize;
 
     
     let (name, data) = if name_len > 0 {
-        let (name, data) = data.split_at(name_len);
-        (Some( String::from_utf8(name.into()).unwrap() ), data)  // !: Fix unwrap
+        let (name, data) = split(data, name_len)?;
+        (Some( String::from_utf8(name.into()).map_err(|_| OptionError::Parse)? ), data)
     } else { (None, data) };
 
-    let (n_info_requests, requests) = data.split_at(2);
+    let (n_info_requests, requests) = split(data, 2)?;
     let n_info_requests = n_info_requests.try_into()
         .map(u16::from_be_bytes)
         .map_err(|_| OptionError::Parse)? as usize;
@@ -114,26 +123,26 @@ fn parse_info_go(data: &[u8]) -> Result<(Option<String>, Option<Vec<Info>>), Opt
 }
 
 fn parse_list_meta_context(data: &[u8]) -> Result<(Option<String>, Option<Vec<String>>), OptionError> {
-    let (name_len, data) = data.split_at(4);
+    let (name_len, data) = split(data, 4)?;
     let name_len: usize = name_len.try_into()
         .map(u32::from_be_bytes)
         .map_err(|_| OptionError::Parse)? as usize;
 
     
     let (name, data) = if name_len > 0 {
-        let (name, data) = data.split_at(name_len);
-        (Some( String::from_utf8(name.into()).unwrap() ), data)  // !: Fix unwrap
+        let (name, data) = split(data, name_len)?;
+        (Some( String::from_utf8(name.into()).map_err(|_| OptionError::Parse)? ), data)
     } else { (None, data) };
 
-    let (n_queries, queries) = data.split_at(4);
+    let (n_queries, queries) = split(data, 4)?;
     let n_queries = n_queries.try_into()
         .map(u32::from_be_bytes)
         .map_err(|_| OptionError::Parse)? as usize;
 
     let (_, queries): (_, Option<Vec<String>>) = if n_queries > 0 {
         (0..n_queries)
-            .try_fold((queries, Vec::with_capacity(n_queries)), |(data, mut acc), _| {
-                let (len, data) = data.split_at(4);
+            .try_fold((queries, Vec::new()), |(data, mut acc), _| {
+                let (len, data) = split(data, 4)?;
                 let len = len.try_into()
                     .map(u32::from_be_bytes)
                     .map_err(|_| OptionError::Parse)? as usize;
@@ -141,7 +150,7 @@ fn parse_list_meta_context(data: &[u8]) -> Result<(Option<String>, Option<Vec<St
                 if data.len() < len { return Err(OptionError::RequireData(len - data.len())) }
                 
                 let (qry, data) = data.split_at(len);
-                let qry = String::from_utf8_lossy(&qry).into_owned();
+                let qry = String::from_utf8_lossy(qry).into_owned();
                 acc.push(qry);
         
                 Ok((data, acc))
@@ -151,24 +160,32 @@ fn parse_list_meta_context(data: &[u8]) -> Result<(Option<String>, Option<Vec<St
     Ok((name, queries))
 }
 
-impl Into<u32> for NbdOption {
-    fn into(self) -> u32 {
-        match self {
-            NbdOption::ExportName => 1,
+//  `split_at` that fails on data shorter than the lengths inside it claim
+fn split(data: &[u8], mid: usize) -> Result<(&[u8], &[u8]), OptionError> {
+    if data.len() < mid { return Err(OptionError::Parse) }
+    Ok(data.split_at(mid))
+}
+
+impl From<NbdOption> for u32 {
+    fn from(option: NbdOption) -> u32 {
+        match option {
+            NbdOption::ExportName(_) => 1,
             NbdOption::Abort => 2,
             NbdOption::List => 3,
             NbdOption::PeekExport => 4,
-            NbdOption::Starttls => 5,
+            NbdOption::Starttls(_) => 5,
             NbdOption::Info(_,_) => 6,
             NbdOption::Go(_,_) => 7,
             NbdOption::StructuredReply(_) => 8,
             NbdOption::ListMetaContext(_,_) => 9, 
-            NbdOption::SetMetaContext => 10,
+            NbdOption::SetMetaContext(_,_) => 10,
+            NbdOption::ExtendedHeaders(_) => 11,
         }
     }
 }
 
 #[derive(FromPrimitive, ToPrimitive, Debug)]
+#[repr(u32)]
 pub enum OptionReplyType {
     Ack = 1,
     Server,
@@ -186,6 +203,7 @@ pub enum OptionReplyType {
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct Reply {
     header: [u8; 20],
     pub option_type: u32,
@@ -217,7 +235,7 @@ impl Message for Reply {
     }
 
     fn get_data(&self) -> Option<&[u8]> {
-        self.data.as_ref().map(Vec::as_slice)
+        self.data.as_deref()
     }
 }
 
@@ -1,8 +1,8 @@
-use std::io::{Read};
-use std::rc::Rc;
+use std::io::{self, Write};
+use std::sync::Arc;
 use std::iter::Iterator;
 
-use crate::protocol::message::Message;
+use crate::protocol::message::{Message, send_msg};
 use crate::export::Export;
 
 use num_derive::{FromPrimitive, ToPrimitive};
@@ -10,6 +10,7 @@ use num_traits::{ToPrimitive};
 
 pub const SIMPLE_REPLY_MAGIC: u32 = 0x67446698;
 const STRUCTURED_REPLY_MAGIC: u32 = 0x668e33ef;
+const EXTENDED_REPLY_MAGIC: u32 = 0x6e8a278c;
 
 pub enum Reply {
     Simple(SimpleReply),
@@ -17,7 +18,19 @@ pub enum Reply {
     Disconnect
 }
 
+impl Reply {
+    /// Writes out every message of the reply, `Disconnect` has none
+    pub fn send<W: Write>(self, stream: &mut W) -> io::Result<()> {
+        match self {
+            Reply::Simple(reply) => send_msg(stream, reply),
+            Reply::Structured(mut replies) => replies.try_for_each(|msg| send_msg(stream, msg)),
+            Reply::Disconnect => Ok(())
+        }
+    }
+}
+
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct SimpleReply {
     header: [u8; 16],
     pub error: u32,
@@ -46,83 +59,217 @@ impl Message for SimpleReply {
     }
 
     fn get_data(&self) -> Option<&[u8]> {
-        self.data.as_ref().map(Vec::as_slice)
+        self.data.as_deref()
     }
 }
 
 pub struct StructuredReply{
     handle: u64,
     count: usize,
-    gen_func: Box<dyn FnMut((u64, u32)) -> Vec<u8>>,
-    ranges: Vec<(u64, u32)>
+    total: usize,
+    gen_func: Box<dyn FnMut(usize) -> (ChunkType, Vec<u8>)>,
+    extended: Option<u64>,  //  request offset, echoed in extended headers
 }
 
 impl StructuredReply {
-    pub fn read_from_offset(source: Rc<Export>, handle: u64, offset: u64, len: u32, chunk_size: u32) -> Self
+    pub fn read_from_offset(source: Arc<Export>, handle: u64, offset: u64, len: u32, chunk_size: u32, dont_fragment: bool) -> Self
     {
-        let end: u64 = offset + len as u64;
-
-        let ranges: Vec<(u64, u32)> = (0..).take_while(|i| i * chunk_size < len)
-            .map(|i| {
-                let start: u64 = offset + (i * chunk_size) as u64;
-                let size: u64 = if start + chunk_size as u64 > end {
-                        end - offset 
-                    } else { chunk_size.into() };
-                (start, size as u32)
-            }).collect();
+        //  content chunks describe at least one byte, an empty read is only the done flag
+        if len == 0 {
+            return Self::from_simple(SimpleReply::new(0, handle, None))
+        }
+
+        //  with DF set the whole range has to go out as one data chunk
+        let sparse = source.sparse_reads && !dont_fragment;
+        let chunk_size = if dont_fragment { len.max(1) } else { chunk_size };
 
+        //  holes go out as a single chunk, data is split into `chunk_size` pieces
+        let extents: Vec<(u64, bool)> = if sparse {
+            source.extents(offset, len.into(), usize::MAX).unwrap_or_else(|_| vec![(len.into(), false)])
+        } else { 
+            vec![(len.into(), false)] 
+        };
+
+        let mut ranges: Vec<(u64, u32, bool)> = Vec::new();
+        let mut start = offset;
+        for (ext_len, hole) in extents {
+            let end: u64 = start + ext_len;
+            if hole {
+                ranges.push((start, ext_len as u32, true));
+            } else {
+                ranges.extend((0..).take_while(|i| i * (chunk_size as u64) < ext_len)
+                    .map(|i| {
+                        let chunk_start: u64 = start + i * chunk_size as u64;
+                        let size: u64 = if chunk_start + chunk_size as u64 > end {
+                                end - chunk_start 
+                            } else { chunk_size.into() };
+                        (chunk_start, size as u32, false)
+                    }));
+            }
+            start = end;
+        }
+
+        let total = ranges.len();
         let gen_func = Box::new(
-            move |arg: (u64, u32)| {
-                let (ofs, len) = arg;
+            move |i: usize| {
+                let (ofs, len, hole) = ranges[i];
+                if hole {
+                    return (ChunkType::OffsetHole, hole_payload(ofs, len))
+                }
+
                 let mut data = vec![0; len as usize + 8];
-                (&ofs.to_be_bytes()[0..]).read(&mut data);
+                data[..8].copy_from_slice(&ofs.to_be_bytes());
                 
-                source.read_into(&mut data[8..], ofs, len as usize).unwrap();
+                //  a failed piece doesn't spoil the rest of the reply, the client sees where it broke
+                if let Err(err) = source.read_into(&mut data[8..], ofs, len as usize) {
+                    return (ChunkType::ErrorOffset, error_offset_payload(error_code(&err), &err.to_string(), ofs))
+                }
 
-                data
+                //  allocated, but zeroed blocks are worth a hole chunk too
+                if sparse && data[8..].iter().all(|b| *b == 0) {
+                    (ChunkType::OffsetHole, hole_payload(ofs, len))
+                } else {
+                    (ChunkType::OffsetData, data)
+                }
             }
         );
 
-        Self { handle, count: 0, gen_func, ranges }
+        Self { handle, count: 0, total, gen_func, extended: None }
     } 
+
+    /// One chunk per metadata context: `(context id, [(extent length, status flags)])`.
+    /// Extended chunks carry 64-bit descriptors, compact ones are limited to 32 bits.
+    pub fn block_status(handle: u64, statuses: Vec<(u32, Vec<(u64, u32)>)>, extended: bool) -> Self {
+        let mut payloads: Vec<Vec<u8>> = statuses.into_iter()
+            .map(|(context_id, extents)| {
+                let mut data = Vec::with_capacity(8 + extents.len() * 16);
+                data.extend_from_slice(&context_id.to_be_bytes());
+                if extended {
+                    data.extend_from_slice(&(extents.len() as u32).to_be_bytes());
+                }
+                extents.iter().for_each(|(len, flags)| {
+                    if extended {
+                        data.extend_from_slice(&len.to_be_bytes());
+                        data.extend_from_slice(&u64::from(*flags).to_be_bytes());
+                    } else {
+                        data.extend_from_slice(&(*len as u32).to_be_bytes());
+                        data.extend_from_slice(&flags.to_be_bytes());
+                    }
+                });
+                data
+            }).collect();
+
+        let type_ = if extended { ChunkType::BlockStatusExt } else { ChunkType::BlockStatus };
+        let total = payloads.len();
+        let gen_func = Box::new(
+            move |i: usize| (type_.clone(), std::mem::take(&mut payloads[i]))
+        );
+
+        Self { handle, count: 0, total, gen_func, extended: None }
+    }
+
+    /// Single-chunk equivalent of a simple reply, for connections that can't use those
+    pub fn from_simple(reply: SimpleReply) -> Self {
+        let (error, data) = (reply.error, reply.data);
+        let gen_func = Box::new(
+            move |_: usize| if error == 0 {
+                (ChunkType::None, Vec::new())
+            } else {
+                (ChunkType::Error, error_payload(error, ""))
+            }
+        );
+
+        //  the only simple replies with payload are plain reads
+        debug_assert!(data.is_none());
+        Self { handle: reply.handle, count: 0, total: 1, gen_func, extended: None }
+    }
+
+    pub fn with_extended_headers(mut self, offset: u64) -> Self {
+        self.extended = Some(offset);
+        self
+    }
+}
+
+/// NBD error code for a failed export operation, anything unexpected is an I/O error
+pub fn error_code(err: &std::io::Error) -> u32 {
+    use std::io::ErrorKind;
+
+    match err.raw_os_error() {
+        Some(libc::EPERM) | Some(libc::EACCES) | Some(libc::EROFS) => 1,   // NBD_EPERM
+        Some(libc::ENOMEM) => 12,                                          // NBD_ENOMEM
+        Some(libc::EINVAL) => 22,                                          // NBD_EINVAL
+        Some(libc::ENOSPC) | Some(libc::EDQUOT) | Some(libc::EFBIG) => 28, // NBD_ENOSPC
+        Some(libc::EOVERFLOW) => 75,                                       // NBD_EOVERFLOW
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => 95,                 // NBD_ENOTSUP
+        Some(libc::ESHUTDOWN) => 108,                                      // NBD_ESHUTDOWN
+        Some(_) => 5,                                                      // NBD_EIO
+        None => match err.kind() {
+            ErrorKind::PermissionDenied => 1,
+            ErrorKind::OutOfMemory => 12,
+            ErrorKind::InvalidInput => 22,
+            ErrorKind::StorageFull => 28,
+            ErrorKind::Unsupported => 95,
+            _ => 5
+        }
+    }
+}
+
+fn error_payload(error: u32, message: &str) -> Vec<u8> {
+    error.to_be_bytes().iter()
+        .chain((message.len() as u16).to_be_bytes().iter())
+        .chain(message.as_bytes())
+        .copied().collect()
+}
+
+fn error_offset_payload(error: u32, message: &str, offset: u64) -> Vec<u8> {
+    let mut payload = error_payload(error, message);
+    payload.extend_from_slice(&offset.to_be_bytes());
+    payload
+}
+
+fn hole_payload(offset: u64, len: u32) -> Vec<u8> {
+    offset.to_be_bytes().iter()
+        .chain(len.to_be_bytes().iter())
+        .copied().collect()
 }
 
 impl Iterator for StructuredReply {
     type Item = StructuredReplyChunk;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.count < self.ranges.len() {
-            let data = (self.gen_func)(self.ranges[self.count]);
+        if self.count < self.total {
+            let (type_, data) = (self.gen_func)(self.count);
             self.count += 1;
-            Some( Self::Item::new(
-                ChunkType::OffsetData,
-                self.handle,
-                Some(data),
-                self.count == self.ranges.len()             
-            ))
+            let done = self.count == self.total;
+            Some( match self.extended {
+                Some(offset) => Self::Item::new_extended(type_, self.handle, offset, Some(data), done),
+                None => Self::Item::new(type_, self.handle, Some(data), done)
+            })
         } else {
             None
         }
     }
 }
 
-#[derive(FromPrimitive, ToPrimitive, Debug)]
+#[derive(FromPrimitive, ToPrimitive, Debug, Clone)]
 pub enum ChunkType {
     None = 0,
     OffsetData,
     OffsetHole,
     BlockStatus = 5,
+    BlockStatusExt,
     Error = (1 << 15) + 1,
     ErrorOffset,
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct StructuredReplyChunk {
-    header: [u8; 20],
+    header: Vec<u8>,           //  20 bytes, or 32 with extended headers
     pub flags: u16,            //  !: only NBD_REPLY_FLAG_DONE on bit 0
     pub type_: ChunkType,      //  u16
     pub handle: u64,
-    pub len: u32,
+    pub len: u64,
     data: Option<Vec<u8>>  //  ?: consider using unwrapped Vec since 
                            //  ?: simple reply can be used for msgs without payload
 }
@@ -132,7 +279,7 @@ impl StructuredReplyChunk {
         let flags: u16 = if done { 1 } else { 0 };
         let len: u32 = data.as_ref().map(Vec::len).unwrap_or(0) as u32;
 
-        let mut header = [0_u8; 20];
+        let mut header = vec![0_u8; 20];
 
         STRUCTURED_REPLY_MAGIC.to_be_bytes().iter().enumerate()
             .for_each(|(i, b)| header[i] = *b);
@@ -145,6 +292,28 @@ impl StructuredReplyChunk {
         len.to_be_bytes().iter().enumerate()
             .for_each(|(i, b)| header[i + 16] = *b);
 
+        Self { header, flags, type_, handle, len: len.into(), data }
+    }
+
+    fn new_extended(type_: ChunkType, handle: u64, offset: u64, data: Option<Vec<u8>>, done: bool) -> Self {
+        let flags: u16 = if done { 1 } else { 0 };
+        let len: u64 = data.as_ref().map(Vec::len).unwrap_or(0) as u64;
+
+        let mut header = vec![0_u8; 32];
+
+        EXTENDED_REPLY_MAGIC.to_be_bytes().iter().enumerate()
+            .for_each(|(i, b)| header[i] = *b);
+        flags.to_be_bytes().iter().enumerate()
+            .for_each(|(i, b)| header[i + 4] = *b);
+        type_.to_u16().unwrap().to_be_bytes().iter().enumerate()
+            .for_each(|(i, b)| header[i + 6] = *b);
+        handle.to_be_bytes().iter().enumerate()
+            .for_each(|(i, b)| header[i + 8] = *b);
+        offset.to_be_bytes().iter().enumerate()
+            .for_each(|(i, b)| header[i + 16] = *b);
+        len.to_be_bytes().iter().enumerate()
+            .for_each(|(i, b)| header[i + 24] = *b);
+
         Self { header, flags, type_, handle, len, data }
     }
 }
@@ -155,6 +324,6 @@ impl Message for StructuredReplyChunk {
     }
 
     fn get_data(&self) -> Option<&[u8]> {
-        self.data.as_ref().map(Vec::as_slice)
+        self.data.as_deref()
     }
 }
@@ -4,20 +4,23 @@ use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::{FromPrimitive};
 
 pub const REQMAGIC: u32 = 0x25609513;
+pub const EXTENDED_REQMAGIC: u32 = 0x21e41c71;
 
 #[derive(Debug)]
 pub struct Flags {
-    fua: bool,
-    no_hole: bool,
-    dont_fragment: bool,
-    request_one: bool,
-    fast_zero: bool,
+    pub fua: bool,
+    pub no_hole: bool,
+    pub dont_fragment: bool,
+    pub request_one: bool,
+    pub fast_zero: bool,
+    pub raw: u16,
 }
 
 impl From<u16> for Flags {
     fn from(flags: u16) -> Self {
         Self {
-            fua:           (flags >> 0 & 1) != 0,
+            raw: flags,
+            fua:           (flags & 1) != 0,
             no_hole:       (flags >> 1 & 1) != 0,
             dont_fragment: (flags >> 2 & 1) != 0,
             request_one:   (flags >> 3 & 1) != 0,
@@ -40,12 +43,13 @@ pub enum RequestType {
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct Request {
     pub type_: RequestType,
     pub flags: Flags,
     pub handle: u64,
     pub offset: u64,
-    pub len: u32,
+    pub len: u64,
     pub data: Option<Vec<u8>>,
 }
 
@@ -83,11 +87,27 @@ impl TryFrom<&[u8]> for Request {
 
             let handle = slice[4..12].try_into().map(u64::from_be_bytes)?;
             let offset = slice[12..20].try_into().map(u64::from_be_bytes)?; 
-            let len =    slice[20..24].try_into().map(u32::from_be_bytes)?;
+            let len =    slice[20..24].try_into().map(u32::from_be_bytes)?.into();
 
             let data = if slice.len() > 24 { Some(slice[24..].into()) } else { None };
 
             Ok( Self { type_, flags, handle, offset, len, data })
         }
     }
+}
+
+impl Request {
+    /// Parses the 32-byte header used after `NBD_OPT_EXTENDED_HEADERS` (magic excluded),
+    /// which differs from the compact one only in the 64-bit length.
+    pub fn from_extended(slice: &[u8]) -> Result<Self, RequestError> {
+        if slice.len() < 28 {
+            Err(RequestError::BufferTooShort)
+        } else {
+            let mut request = Self::try_from(&slice[..24])?;
+            request.len = slice[20..28].try_into().map(u64::from_be_bytes)?;
+            request.data = if slice.len() > 28 { Some(slice[28..].into()) } else { None };
+
+            Ok(request)
+        }
+    }
 }
\ No newline at end of file
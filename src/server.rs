@@ -1,337 +1,243 @@
 use std::io::prelude::*;
-use std::net::TcpStream;
-use std::convert::TryInto;
-use std::rc::Rc;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
 
-use crate::export::{Export};
+use crate::export::Exports;
+use crate::session::{self, Payload, Session, Step};
+use crate::tls::{Stream, Tls};
+use crate::transport::Transport;
 use crate::protocol::request as req;
-use crate::protocol::reply as rpl;
-use crate::protocol::option as opt;
-use crate::protocol::send_msg;
 
-use num_traits::{ToPrimitive};
-
-const HS_FLAGS: u16 = 0b0000000000000011; // !NO_ZEROS && FIXED NEWSTYLE
-const TRANSMISSION_FLAGS: u16 = 0b0000000000000011;
-
-const NBDMAGIC: u64 = 0x4e42444d41474943;
-const IHAVEOPT: u64 = 0x49484156454F5054;
-
-// TODO: make generic over input_stream
-pub struct Server {
-    chunk_size: u32,
-    ready: bool,
-    use_structured: bool,
-    input_stream: TcpStream,
-    export: Rc<Export>,
+//  threads handling the requests of one connection
+const WORKERS: usize = 4;
+//  requests read ahead of their replies, per connection
+pub const MAX_IN_FLIGHT: usize = 16;
+
+/// One client connection over any `Transport`: TCP, Unix sockets, stdio or whatever
+/// the embedding program hands in.
+pub struct Server<T: Transport> {
+    session: Session,
+    input_stream: Stream<T>,
+    tls: Tls,
 }
 
 // ?: move this to protocol
-fn handshake(stream: &mut TcpStream) -> std::io::Result<()> {
+fn handshake<T: Read + Write>(stream: &mut T) -> std::io::Result<u32> {
     let mut buf = [0; 4];
 
-    stream.write_all(&NBDMAGIC.to_be_bytes())?;
-    stream.write_all(&IHAVEOPT.to_be_bytes())?;
-    stream.write_all(&HS_FLAGS.to_be_bytes())?;  // TODO: fix this
+    stream.write_all(&session::greeting())?;
     stream.flush()?;
     stream.read_exact(&mut buf)?;
 
-    // if u32::from_be_bytes(buf) != HS_FLAGS as u32 { panic!("wrong client flags"); }
-
-    Ok(())
+    Ok(u32::from_be_bytes(buf))
 }
 
-impl Server {
-    pub fn handshake(export_name: &str, mut stream: TcpStream, chunk_size: u32) -> Result<Self, ServerError> {
+impl<T: Transport> Server<T> {
+    pub fn handshake(exports: Arc<Exports>, mut stream: T, chunk_size: u32, tls: Tls) -> Result<Self, ServerError> {
         
-        handshake(&mut stream)?;
-        let use_structured = false;
+        let client_flags = handshake(&mut stream)?;
+        let session = Session::new(exports, chunk_size, tls.mode, client_flags)?;
 
-        let export = Rc::new(Export::new(export_name.to_owned())?);
+        Ok ( Self {
+            session,
+            input_stream: Stream::Plain(stream),
+            tls,
+        })
+    }
+
+    /// Oldstyle negotiation: the export is announced right away and there are no options,
+    /// so the server is ready for transmission once this returns.
+    pub fn oldstyle(exports: Arc<Exports>, mut stream: T, chunk_size: u32) -> Result<Self, ServerError> {
+        let (session, greeting) = Session::oldstyle(exports, chunk_size)?;
+
+        stream.write_all(&greeting)?;
+        stream.flush()?;
 
         Ok ( Self {
-            chunk_size,
-            ready: false,
-            use_structured,
-            input_stream: stream,
-            export
+            session,
+            input_stream: Stream::Plain(stream),
+            tls: Tls::off(),
         })
     }
 
     pub fn option_haggle(mut self) -> Result<Self, ServerError> {
-        let mut input_buffer = vec![0; 1024];
-        let (mut end, mut unparsed) = (0, false);
-        
-        loop {
-            
-            if !unparsed {
-                let mut buf = [0; 8];
-                self.input_stream.read_exact(&mut buf)?;
-                assert_eq!(&buf, b"IHAVEOPT");
-
-                let bytes_read = self.input_stream.read(&mut input_buffer)?;
-                end = 0 + bytes_read;
-            }
-            unparsed = false;
-
-            match input_buffer[..end].try_into() {
-                Ok(option) => {
-                    println!("got opt: {:?}", option);
-                    match option {
-                        opt::NbdOption::Info(_, info_requests) => {
-                            let mut infos = Self::get_info(Rc::clone(&self.export), info_requests);
-
-                            infos.try_for_each(|reply|
-                                send_msg(&mut self.input_stream, reply)
-                            )?;
-
-                            let reply = opt::Reply::new(
-                                opt::NbdOption::Info(None, None).into(), 
-                                opt::OptionReplyType::Ack, 
-                                None
-                            );
-
-                            send_msg(&mut self.input_stream, reply)?;
-                        },
-
-                        opt::NbdOption::Go(_name, _infos) => {
-                            let info: Vec<u8> = opt::Info::Export.to_u16().unwrap()
-                                .to_be_bytes().iter()
-                                .chain(self.export.size.to_be_bytes().iter())
-                                .chain(TRANSMISSION_FLAGS.to_be_bytes().iter())
-                                .copied().collect();
-
-                            let reply = opt::Reply::new(
-                                opt::NbdOption::Go(None, None).into(), 
-                                opt::OptionReplyType::Info, 
-                                Some(info)
-                            );
-
-                            send_msg(&mut self.input_stream, reply)?;
-
-                            let reply = opt::Reply::new(
-                                opt::NbdOption::Go(None, None).into(), 
-                                opt::OptionReplyType::Ack, 
-                                None
-                            );
-                            
-                            send_msg(&mut self.input_stream, reply)?;
-                            
-                            break;
-                        },
-
-                        //  TODO: fix this
-                        opt::NbdOption::ExportName => {
-                            let reply = opt::Reply::new(
-                                opt::NbdOption::ExportName.into(), 
-                                opt::OptionReplyType::ErrUnsup, 
-                                None
-                            );
-
-                            send_msg(&mut self.input_stream, reply)?;
-                        },
-
-                        opt::NbdOption::StructuredReply(allow) => {             //  The client MUST NOT send any additional data
-                            let reply = opt::Reply::new(                        //  with the option, and the server SHOULD reject
-                                opt::NbdOption::StructuredReply(false).into(),  //  a request that includes data with 
-                                if allow { opt::OptionReplyType::Ack }          //  `NBD_REP_ERR_INVALID`
-                                else { opt::OptionReplyType::ErrInvalid },
-                                None
-                            );
-
-                            send_msg(&mut self.input_stream, reply)?;
-                            self.use_structured = allow;
-                        }
-
-                        opt::NbdOption::List => {                            
-                            let data = (self.export.name.len() as u32).to_be_bytes().iter()
-                                .chain(self.export.name.as_bytes())
-                                .copied()
-                                .collect();
-                            
-                            let reply = opt::Reply::new(
-                                opt::NbdOption::List.into(),
-                                opt::OptionReplyType::Server,
-                                Some(data)
-                            );
-
-                            send_msg(&mut self.input_stream, reply)?;
-
-                            let reply = opt::Reply::new(
-                                opt::NbdOption::List.into(),
-                                opt::OptionReplyType::Ack,
-                                None
-                            );
-
-                            send_msg(&mut self.input_stream, reply)?;
-                        }
-
-                        opt::NbdOption::Abort => {
-                            use std::io::{Error, ErrorKind};
-
-                            let reply = opt::Reply::new(
-                                opt::NbdOption::Abort.into(),
-                                opt::OptionReplyType::Ack,
-                                None
-                            );
-
-                            send_msg(&mut self.input_stream, reply)?;
-                            return Err(ServerError::Abort)
-                        }
-
-                        ot => {
-                            let reply = opt::Reply::new(
-                                ot.into(), 
-                                opt::OptionReplyType::ErrUnsup, 
-                                None
-                            );
-
-                            send_msg(&mut self.input_stream, reply)?;
-                        },
-                    }
-                },
+        let mut header = [0; session::OPTION_HEADER_LEN];
+        let mut replies = Vec::new();
 
-                Err(opt::OptionError::RequireData(how_much)) => {                    
-                    let bytes_read = self.input_stream.read(&mut input_buffer[end..(end+how_much)])?;
-                    unparsed = true;
-                    end += bytes_read;
-                }
+        loop {
+            self.input_stream.read_exact(&mut header)?;
+            let (option, len) = session::option_header(&header)?;
 
-                Err(e) => {
-                    println!("option parse error: {:?}", e); 
-                }
+            let step = if len > session::MAX_OPTION_LEN {
+                let skipped = std::io::copy(&mut (&mut self.input_stream).take(len as u64), &mut std::io::sink())?;
+                if skipped < len as u64 { return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()) }
+                self.session.oversized_option(option, &mut replies)?
+            } else {
+                let mut raw = header[8..].to_vec();
+                raw.resize(8 + len, 0);
+                self.input_stream.read_exact(&mut raw[8..])?;
+                self.session.option(&raw, &mut replies)?
+            };
+
+            self.input_stream.write_all(&replies)?;
+            self.input_stream.flush()?;
+            replies.clear();
+
+            match step {
+                Step::Continue => {},
+                Step::StartTls => {
+                    self.input_stream = self.input_stream.start_tls(&self.tls)
+                        .map_err(ServerError::TlsHandshake)?;
+                    self.session.tls_started(self.tls.peer(&self.input_stream));
+                },
+                Step::Transmission => break,
+                Step::Abort => return Err(ServerError::Abort),
             }
         }
 
-        self.ready = true;
-        Rc::get_mut(&mut self.export).unwrap().load()?;  //  should not fail
         Ok(self)
     }
 
-    pub fn serve(&mut self) -> Result<(), ServerError> {
-        use std::io::{Error, ErrorKind};
+    /// Transmission phase. Requests are read as they come and handed to a pool of workers,
+    /// replies go out in the order they complete, matched by handle.
+    pub fn serve(self) -> Result<(), ServerError> {
+        if !self.session.is_ready() { return Err(ServerError::NotReady) }
+
+        let session = Arc::new(self.session);
+        let (mut stream, mut writer) = match self.input_stream.split()? {
+            Ok(halves) => halves,
+            Err(mut stream) => return serve_in_order(&session, &mut stream)
+        };
+
+        let in_flight = Arc::new(InFlight::default());
+        let (jobs, job_queue) = mpsc::channel::<req::Request>();
+        let job_queue = Arc::new(Mutex::new(job_queue));
+        let (replies, reply_queue) = mpsc::channel::<Vec<u8>>();
+
+        //  a request counts as answered once its reply is written, so replies stuck behind
+        //  a client that doesn't read them hold up new requests
+        let writing = {
+            let in_flight = Arc::clone(&in_flight);
+            thread::spawn(move || {
+                let mut written = Ok(());
+                for reply in reply_queue {
+                    if written.is_ok() {
+                        written = writer.write_all(&reply).and_then(|_| writer.flush());
+                    }
+                    in_flight.done();
+                }
+                written
+            })
+        };
+
+        let workers: Vec<_> = (0..WORKERS).map(|_| {
+            let (session, job_queue, replies) = (Arc::clone(&session), Arc::clone(&job_queue), replies.clone());
+
+            thread::spawn(move || loop {
+                let request = match job_queue.lock().unwrap().recv() {
+                    Ok(request) => request,
+                    Err(_) => break
+                };
+
+                replies.send(session.reply(request)).expect("the writer outlives the workers");
+            })
+        }).collect();
+        drop(replies);
+
+        let mut header_buf = vec![0; session.request_header_len()];
+        let result = loop {
+            let request = match read_request(&session, &mut stream, &mut header_buf) {
+                Ok(request) => request,
+                Err(err) => break Err(err)
+            };
+
+            if session.is_barrier(&request) {
+                in_flight.wait_idle();
+            }
+            if let req::RequestType::Disc = request.type_ {
+                eprintln!("disconnecting");
+                break Ok(())
+            }
 
-        if !self.ready { return Err(ServerError::NotReady) }
-        
-        let mut magic_buf = [0; 4];
+            in_flight.start();
+            jobs.send(request).expect("workers outlive the job queue");
+        };
 
-        let mut header_buf = [0; 24];
+        //  requests already read are still answered
+        drop(jobs);
+        workers.into_iter().for_each(|worker| worker.join().expect("request worker panicked"));
+        let written = writing.join().expect("reply writer panicked");
 
-        loop {
+        result.and(written.map_err(ServerError::from))
+    }
+}
 
-            // ?: consider moving magic check to request code   
-            self.input_stream.read_exact(&mut magic_buf).unwrap();
-            if u32::from_be_bytes(magic_buf) != req::REQMAGIC {
-                eprintln!("error: wrong request magic, disconnecting");
-                return Err(ServerError::Unsync)
-            }
-            
-            let bytes_read = self.input_stream.read(&mut header_buf).unwrap();
-
-            if bytes_read == 24 {
- 
-                match header_buf[..bytes_read].try_into() {
-                    Ok(r) => {
-                        let reply_ = self.handle_request(r); 
-                        match reply_ {
-                            rpl::Reply::Disconnect => {
-                                eprintln!("disconnecting");
-                                break
-                            },
-
-                            rpl::Reply::Simple(reply) => {
-                                send_msg(&mut self.input_stream, reply).unwrap()
-                            },
-
-                            rpl::Reply::Structured(replies) => {
-                                let s = &mut self.input_stream;
-                                replies.for_each(|msg| send_msg(s, msg).unwrap())
-                            }
-                        }
-                    },
-                    Err(e) => {
-                        eprintln!("error: {:?}", e);
-                        return Err(e.into())
-                    }
-                }
+//  for transports that can't be read and written from different threads
+fn serve_in_order<T: Transport>(session: &Session, stream: &mut Stream<T>) -> Result<(), ServerError> {
+    let mut header_buf = vec![0; session.request_header_len()];
 
-            } else {
-                eprintln!("request message too short");
-            }
+    loop {
+        let request = read_request(session, stream, &mut header_buf)?;
+        if let req::RequestType::Disc = request.type_ {
+            eprintln!("disconnecting");
+            break
         }
 
-        Ok(())
+        stream.write_all(&session.reply(request))?;
+        stream.flush()?;
     }
 
-    fn handle_request(&self, request: req::Request) -> rpl::Reply {
-        match request.type_ {
-            req::RequestType::Read => {
-                if request.offset + request.len as u64 > self.export.size {
-                    return rpl::Reply::Simple(rpl::SimpleReply::new(22, request.handle, None))  // NBD_EINVAL
-                }
-                if self.use_structured {
-                    rpl::Reply::Structured(
-                        rpl::StructuredReply::read_from_offset(
-                            Rc::clone(&self.export), request.handle, request.offset, request.len, self.chunk_size
-                        )
-                    )
-                } else {
-                    rpl::Reply::Simple( 
-                        rpl::SimpleReply::new(
-                            0, request.handle, Some(self.export.read(request.offset, request.len as usize).unwrap())
-                        )
-                    )
-                }
-            },
-            req::RequestType::Disc => rpl::Reply::Disconnect,
+    Ok(())
+}
 
-            //  unimplemented requests
-            _ => rpl::Reply::Simple(rpl::SimpleReply::new(22, request.handle, None))  // NBD_EINVAL
+//  the header length is fixed once transmission starts
+fn read_request<R: Read>(session: &Session, stream: &mut R, header_buf: &mut [u8]) -> Result<req::Request, ServerError> {
+    stream.read_exact(header_buf)?;
+    let mut request = session.parse_request(header_buf)?;
+
+    match session.payload(&request) {
+        Payload::None => {},
+        Payload::Read(len) => {
+            let mut payload = vec![0; len];
+            stream.read_exact(&mut payload)?;
+            request.data = Some(payload);
+        },
+        Payload::Skip(len) => {
+            let skipped = std::io::copy(&mut stream.take(len), &mut std::io::sink())?;
+            if skipped < len { return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()) }
         }
     }
 
-    fn get_info(export: Rc<Export>, info_requests: Option<Vec<opt::Info>>) -> impl Iterator<Item = opt::Reply> {
-        let export_size: u64 = export.size;
-        let export_name: String = export.name.clone();
+    Ok(request)
+}
 
-        let mut info_requests = info_requests.unwrap_or(Vec::new());
-        if !info_requests.contains(&opt::Info::Export) { 
-            info_requests.insert(0, opt::Info::Export); 
+/// Requests handed to the workers and not answered yet
+#[derive(Default)]
+struct InFlight {
+    count: Mutex<usize>,
+    changed: Condvar,
+}
+
+impl InFlight {
+    //  waits for room below `MAX_IN_FLIGHT`, which bounds the memory a client can tie up
+    fn start(&self) {
+        let mut count = self.count.lock().unwrap();
+        while *count >= MAX_IN_FLIGHT {
+            count = self.changed.wait(count).unwrap();
         }
+        *count += 1;
+    }
 
-        info_requests.into_iter()
-                .map(move |info_type| match info_type {
-                    opt::Info::Export => 
-                        opt::Info::Export.to_u16().unwrap()
-                            .to_be_bytes().iter()
-                            .chain(export_size.to_be_bytes().iter())
-                            .chain(TRANSMISSION_FLAGS.to_be_bytes().iter())
-                            .copied().collect(),
-                    
-                    opt::Info::Name => 
-                        opt::Info::Name.to_u16().unwrap()
-                            .to_be_bytes().iter()
-                            .chain(export_name.clone().into_bytes().iter())
-                            .copied().collect(),
-
-                    opt::Info::Description =>
-                        opt::Info::Description.to_u16().unwrap()
-                            .to_be_bytes().iter()
-                            .copied().collect(),
-
-                    opt::Info::BlockSize =>
-                        opt::Info::BlockSize.to_u16().unwrap()
-                            .to_be_bytes().iter()
-                            .chain(512_u32.to_be_bytes().iter())
-                            .chain(4096_u32.to_be_bytes().iter())
-                            .chain(4096_u32.to_be_bytes().iter())
-                            .copied().collect(),
-                
-                }).map(|data| opt::Reply::new(
-                    opt::NbdOption::Info(None, None).into(), 
-                    opt::OptionReplyType::Info, 
-                    Some(data)
-                ))
+    fn done(&self) {
+        *self.count.lock().unwrap() -= 1;
+        self.changed.notify_all();
+    }
+
+    fn wait_idle(&self) {
+        let mut count = self.count.lock().unwrap();
+        while *count > 0 {
+            count = self.changed.wait(count).unwrap();
+        }
     }
 }
 
@@ -341,6 +247,10 @@ pub enum ServerError {
     IoError(std::io::Error),
     Abort,
     NotReady,
+    TlsRequired,
+    TlsHandshake(std::io::Error),
+    ClientFlags(u32),
+    ExportRefused(String),
     Unsync
 }
 
@@ -354,4 +264,261 @@ impl From<req::RequestError> for ServerError {
     fn from(err: req::RequestError) -> Self {
         Self::RequestError(err)
     }
-}
\ No newline at end of file
+}
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::RequestError(err) => write!(f, "bad request: {:?}", err),
+            Self::IoError(err) => write!(f, "io error: {}", err),
+            Self::Abort => write!(f, "client aborted"),
+            Self::NotReady => write!(f, "server is not ready for transmission"),
+            Self::TlsRequired => write!(f, "client tried to skip required tls"),
+            Self::TlsHandshake(err) => write!(f, "tls handshake failed: {}", err),
+            Self::ClientFlags(flags) => write!(f, "unknown client flags: {:#x}", flags),
+            Self::ExportRefused(name) => write!(f, "refused export {:?}", name),
+            Self::Unsync => write!(f, "lost sync with the client"),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::fs::OpenOptions;
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+    use crate::export::{Export, ExportOptions, DEFAULT_MAX_PAYLOAD};
+    use crate::protocol::reply as rpl;
+    use crate::testing::ScratchFile;
+
+    const NBD_OPT_GO: u32 = 7;
+    const NBD_OPT_SET_META_CONTEXT: u32 = 10;
+    const NBD_OPT_EXTENDED_HEADERS: u32 = 11;
+    const NBD_REP_ACK: u32 = 1;
+    const NBD_REP_INFO: u32 = 3;
+    const NBD_REP_META_CONTEXT: u32 = 4;
+    const NBD_REP_ERR_BLOCK_SIZE_REQD: u32 = (1 << 31) + 8;
+    const NBD_INFO_BLOCK_SIZE: u16 = 3;
+    const NBD_EXTENDED_REPLY_MAGIC: u32 = 0x6e8a278c;
+    const NBD_REPLY_TYPE_BLOCK_STATUS_EXT: u16 = 6;
+
+    fn writable(image: &ScratchFile, max_payload: u32) -> Export {
+        let options = ExportOptions { read_only: false, max_payload, ..ExportOptions::default() };
+        Export::new("disk".to_owned(), image.path(), &options).unwrap()
+    }
+
+    //  a session on the other end of a socket pair, past the greeting
+    fn connect(export: Export) -> (UnixStream, thread::JoinHandle<Result<(), ServerError>>) {
+        let exports = Arc::new(Exports::new(vec![export], None).unwrap());
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let serving = thread::spawn(move || {
+            Server::handshake(exports, server, 4096, Tls::off())
+                .and_then(Server::option_haggle)
+                .and_then(Server::serve)
+        });
+
+        let mut greeting = [0; 18];
+        client.read_exact(&mut greeting).unwrap();
+        assert_eq!(&greeting[..16], b"NBDMAGICIHAVEOPT");
+        client.write_all(&1u32.to_be_bytes()).unwrap();  //  NBD_FLAG_C_FIXED_NEWSTYLE
+
+        (client, serving)
+    }
+
+    //  type and data of the replies to an option, up to the ACK or error that ends them
+    fn option_replies(client: &mut UnixStream, option: u32, data: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let header = [&b"IHAVEOPT"[..], &option.to_be_bytes(), &(data.len() as u32).to_be_bytes()].concat();
+        client.write_all(&[&header, data].concat()).unwrap();
+
+        let mut replies = Vec::new();
+        loop {
+            let mut header = [0; 20];
+            client.read_exact(&mut header).unwrap();
+            let reply_type = u32::from_be_bytes(header[12..16].try_into().unwrap());
+            let mut data = vec![0; u32::from_be_bytes(header[16..].try_into().unwrap()) as usize];
+            client.read_exact(&mut data).unwrap();
+
+            replies.push((reply_type, data));
+            if reply_type == NBD_REP_ACK || reply_type & (1 << 31) != 0 {
+                return replies
+            }
+        }
+    }
+
+    //  the replies before the ACK of an option that has to succeed
+    fn option(client: &mut UnixStream, option: u32, data: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut replies = option_replies(client, option, data);
+        assert_eq!(replies.pop().map(|(reply_type, _)| reply_type), Some(NBD_REP_ACK));
+        replies
+    }
+
+    fn go_data(infos: &[u16]) -> Vec<u8> {
+        let mut data = [&4u32.to_be_bytes()[..], b"disk", &(infos.len() as u16).to_be_bytes()].concat();
+        infos.iter().for_each(|info| data.extend_from_slice(&info.to_be_bytes()));
+        data
+    }
+
+    fn go(client: &mut UnixStream) -> Vec<(u32, Vec<u8>)> {
+        option(client, NBD_OPT_GO, &go_data(&[]))
+    }
+
+    fn request(type_: u16, handle: u64, offset: u64, len: u32) -> Vec<u8> {
+        req::REQMAGIC.to_be_bytes().iter()
+            .chain(0u16.to_be_bytes().iter())
+            .chain(type_.to_be_bytes().iter())
+            .chain(handle.to_be_bytes().iter())
+            .chain(offset.to_be_bytes().iter())
+            .chain(len.to_be_bytes().iter())
+            .copied().collect()
+    }
+
+    fn extended_request(type_: u16, flags: u16, handle: u64, offset: u64, len: u64) -> Vec<u8> {
+        req::EXTENDED_REQMAGIC.to_be_bytes().iter()
+            .chain(flags.to_be_bytes().iter())
+            .chain(type_.to_be_bytes().iter())
+            .chain(handle.to_be_bytes().iter())
+            .chain(offset.to_be_bytes().iter())
+            .chain(len.to_be_bytes().iter())
+            .copied().collect()
+    }
+
+    //  error and handle of the next simple reply
+    fn simple_reply(client: &mut UnixStream) -> (u32, u64) {
+        let mut reply = [0; 16];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(reply[..4], rpl::SIMPLE_REPLY_MAGIC.to_be_bytes());
+        (u32::from_be_bytes(reply[4..8].try_into().unwrap()), u64::from_be_bytes(reply[8..].try_into().unwrap()))
+    }
+
+    //  context id and `(length, flags)` descriptors of a block status chunk that ends its reply
+    fn extended_block_status(client: &mut UnixStream, handle: u64, offset: u64) -> (u32, Vec<(u64, u64)>) {
+        let mut header = [0; 32];
+        client.read_exact(&mut header).unwrap();
+        assert_eq!(header[..4], NBD_EXTENDED_REPLY_MAGIC.to_be_bytes());
+        assert_eq!(header[4..6], 1u16.to_be_bytes());  //  NBD_REPLY_FLAG_DONE
+        assert_eq!(header[6..8], NBD_REPLY_TYPE_BLOCK_STATUS_EXT.to_be_bytes());
+        assert_eq!(header[8..16], handle.to_be_bytes());
+        assert_eq!(header[16..24], offset.to_be_bytes());
+
+        let mut data = vec![0; u64::from_be_bytes(header[24..].try_into().unwrap()) as usize];
+        client.read_exact(&mut data).unwrap();
+        let count = u32::from_be_bytes(data[4..8].try_into().unwrap()) as usize;
+        assert_eq!(data.len(), 8 + count * 16);
+
+        let descriptors = data[8..].chunks(16)
+            .map(|d| (u64::from_be_bytes(d[..8].try_into().unwrap()), u64::from_be_bytes(d[8..].try_into().unwrap())))
+            .collect();
+        (u32::from_be_bytes(data[..4].try_into().unwrap()), descriptors)
+    }
+
+    #[test]
+    fn write_read_back_over_a_socket_pair() {
+        let image = ScratchFile::new("disk.img", &vec![0; 1 << 20]);
+        let (mut client, serving) = connect(writable(&image, DEFAULT_MAX_PAYLOAD));
+
+        let size = go(&mut client).into_iter()
+            .find(|(reply_type, data)| *reply_type == NBD_REP_INFO && data[..2] == [0, 0])
+            .map(|(_, data)| u64::from_be_bytes(data[2..10].try_into().unwrap()));
+        assert_eq!(size, Some(1 << 20));
+
+        let payload: Vec<u8> = (0..4096).map(|i| i as u8).collect();
+        client.write_all(&request(1, 1, 8192, 4096)).unwrap();  //  NBD_CMD_WRITE
+        client.write_all(&payload).unwrap();
+        assert_eq!(simple_reply(&mut client), (0, 1));
+
+        client.write_all(&request(0, 2, 8192, 4096)).unwrap();  //  NBD_CMD_READ
+        assert_eq!(simple_reply(&mut client), (0, 2));
+        let mut data = vec![0; 4096];
+        client.read_exact(&mut data).unwrap();
+        assert_eq!(data, payload);
+
+        client.write_all(&request(2, 3, 0, 0)).unwrap();  //  NBD_CMD_DISC
+        assert!(serving.join().unwrap().is_ok());
+        assert_eq!(std::fs::read(image.path()).unwrap()[8192..12288], payload[..]);
+    }
+
+    #[test]
+    fn block_status_with_extended_headers() {
+        //  64 KiB of data, the rest a hole
+        let image = ScratchFile::new("extended.img", &vec![1; 64 << 10]);
+        OpenOptions::new().write(true).open(image.path()).unwrap().set_len(1 << 20).unwrap();
+        let (mut client, serving) = connect(writable(&image, DEFAULT_MAX_PAYLOAD));
+
+        assert!(option(&mut client, NBD_OPT_EXTENDED_HEADERS, &[]).is_empty());
+        let query = b"base:allocation";
+        let set = [
+            &4u32.to_be_bytes()[..], b"disk", &1u32.to_be_bytes(), &(query.len() as u32).to_be_bytes(), query,
+        ].concat();
+        let contexts = option(&mut client, NBD_OPT_SET_META_CONTEXT, &set);
+        assert_eq!(contexts.len(), 1);
+        assert_eq!((contexts[0].0, &contexts[0].1[4..]), (NBD_REP_META_CONTEXT, &query[..]));
+        let context_id = u32::from_be_bytes(contexts[0].1[..4].try_into().unwrap());
+        go(&mut client);
+
+        client.write_all(&extended_request(7, 0, 1, 0, 1 << 20)).unwrap();  //  NBD_CMD_BLOCK_STATUS
+        assert_eq!(extended_block_status(&mut client, 1, 0), (context_id, vec![
+            (64 << 10, 0),
+            ((1 << 20) - (64 << 10), 3),  //  NBD_STATE_HOLE | NBD_STATE_ZERO
+        ]));
+
+        //  NBD_CMD_FLAG_REQ_ONE
+        client.write_all(&extended_request(7, 1 << 3, 2, 4096, (1 << 20) - 4096)).unwrap();
+        assert_eq!(extended_block_status(&mut client, 2, 4096), (context_id, vec![(60 << 10, 0)]));
+
+        client.write_all(&extended_request(2, 0, 3, 0, 0)).unwrap();  //  NBD_CMD_DISC
+        assert!(serving.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn oversized_requests_get_einval() {
+        let image = ScratchFile::new("oversized.img", &vec![0; 1 << 20]);
+        let (mut client, serving) = connect(writable(&image, 64 << 10));
+        go(&mut client);
+
+        client.write_all(&request(0, 1, 0, (64 << 10) + 512)).unwrap();  //  NBD_CMD_READ
+        assert_eq!(simple_reply(&mut client), (22, 1));  //  NBD_EINVAL
+
+        //  the payload of a refused write is skipped, the next request is read from after it
+        client.write_all(&request(1, 2, 0, (64 << 10) + 512)).unwrap();  //  NBD_CMD_WRITE
+        client.write_all(&vec![0xff; (64 << 10) + 512]).unwrap();
+        assert_eq!(simple_reply(&mut client), (22, 2));
+
+        client.write_all(&request(0, 3, 0, 64 << 10)).unwrap();
+        assert_eq!(simple_reply(&mut client), (0, 3));
+        let mut data = vec![0xff; 64 << 10];
+        client.read_exact(&mut data).unwrap();
+        assert!(data.iter().all(|b| *b == 0));
+
+        client.write_all(&request(2, 4, 0, 0)).unwrap();  //  NBD_CMD_DISC
+        assert!(serving.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn block_sizes_come_before_block_size_reqd() {
+        let image = ScratchFile::new("sectors.img", &vec![0; 1 << 20]);
+        let mut export = writable(&image, 64 << 10);
+        export.block_size.min = 512;  //  like a block device with 512 byte sectors
+        let (mut client, serving) = connect(export);
+
+        let block_size = [
+            &NBD_INFO_BLOCK_SIZE.to_be_bytes()[..], &512u32.to_be_bytes(),
+            &4096u32.to_be_bytes(), &(64u32 << 10).to_be_bytes(),
+        ].concat();
+        let replies = option_replies(&mut client, NBD_OPT_GO, &go_data(&[]));
+        assert_eq!(replies, [(NBD_REP_INFO, block_size.clone()), (NBD_REP_ERR_BLOCK_SIZE_REQD, Vec::new())]);
+
+        //  asking for the block sizes is enough, the client is then trusted to honour them
+        let replies = option(&mut client, NBD_OPT_GO, &go_data(&[NBD_INFO_BLOCK_SIZE]));
+        assert!(replies.contains(&(NBD_REP_INFO, block_size)));
+
+        client.write_all(&request(0, 1, 256, 512)).unwrap();  //  NBD_CMD_READ, not sector aligned
+        assert_eq!(simple_reply(&mut client), (22, 1));
+
+        client.write_all(&request(2, 2, 0, 0)).unwrap();  //  NBD_CMD_DISC
+        assert!(serving.join().unwrap().is_ok());
+    }
+}
@@ -0,0 +1,788 @@
+//  Protocol state of one connection, kept apart from the transport. The blocking and the
+//  async server read and write the bytes, a `Session` decides what they mean: it answers
+//  options into a buffer and turns requests into replies.
+
+use std::convert::{TryFrom, TryInto};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+use crate::export::{BlockSize, Export, Exports};
+use crate::meta_context::{MetaContext, Registry};
+use crate::server::ServerError;
+use crate::tls::{Peer, TlsMode};
+use crate::protocol::request as req;
+use crate::protocol::reply as rpl;
+use crate::protocol::option as opt;
+use crate::protocol::send_msg;
+
+use num_traits::{ToPrimitive};
+
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+const HS_FLAGS: u16 = NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES;
+
+const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+const CLIENT_FLAGS: u32 = HS_FLAGS as u32;  //  client flags mirror the handshake ones
+
+const NBD_FLAG_HAS_FLAGS: u16 = 1 << 0;
+const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+const NBD_FLAG_SEND_FUA: u16 = 1 << 3;
+const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+const NBD_FLAG_SEND_DF: u16 = 1 << 7;
+const NBD_FLAG_CAN_MULTI_CONN: u16 = 1 << 8;
+const NBD_FLAG_SEND_RESIZE: u16 = 1 << 9;
+const NBD_FLAG_SEND_CACHE: u16 = 1 << 10;
+const NBD_FLAG_SEND_FAST_ZERO: u16 = 1 << 11;
+
+//  largest read that is sent as one chunk when the client forbids fragmentation
+const MAX_DF_PAYLOAD: u32 = 32 << 20;
+
+//  descriptors per context in a block status reply, the client asks again for the rest
+const MAX_EXTENTS: usize = 64 << 10;
+
+const NBD_STATE_HOLE: u32 = 1 << 0;
+const NBD_STATE_ZERO: u32 = 1 << 1;
+const NBD_STATE_DIRTY: u32 = 1 << 0;  //  qemu:dirty-bitmap
+
+const NBDMAGIC: u64 = 0x4e42444d41474943;
+const IHAVEOPT: u64 = 0x49484156454F5054;
+const CLISERV_MAGIC: u64 = 0x00420281861253;
+
+/// Option header: IHAVEOPT, option type and data length
+pub const OPTION_HEADER_LEN: usize = 16;
+
+//  names and queries are capped at 4k by the protocol, anything much longer is garbage
+pub const MAX_OPTION_LEN: usize = 64 << 10;
+
+/// What the transport has to do once an option was answered
+pub enum Step {
+    Continue,
+    StartTls,      //  upgrade the connection, then report the peer with `tls_started`
+    Transmission,
+    Abort,
+}
+
+/// Bytes following a request header
+pub enum Payload {
+    None,
+    Read(usize),  //  write data, goes into `Request::data`
+    Skip(u64),    //  write data that is going to be rejected anyway
+}
+
+pub struct Session {
+    chunk_size: u32,
+    use_structured: bool,
+    use_extended: bool,
+    meta_contexts: Vec<(u32, MetaContext)>,
+    no_zeroes: bool,
+    block_size_asked: bool,  //  by INFO or GO, clients that never did can't use sector-sized exports
+    tls_mode: TlsMode,
+    is_tls: bool,
+    peer: Option<Peer>,  //  set once the connection is upgraded
+    exports: Arc<Exports>,
+    meta_export: Option<Arc<Export>>,  //  export the meta contexts were set for
+    export: Option<Arc<Export>>,       //  selected by GO or EXPORT_NAME
+}
+
+/// Newstyle greeting, the client answers with its 32-bit flags
+pub fn greeting() -> Vec<u8> {
+    NBDMAGIC.to_be_bytes().iter()
+        .chain(IHAVEOPT.to_be_bytes().iter())
+        .chain(HS_FLAGS.to_be_bytes().iter())
+        .copied().collect()
+}
+
+/// Option type and data length from an option header
+pub fn option_header(header: &[u8]) -> Result<(u32, usize), ServerError> {
+    if header.len() < OPTION_HEADER_LEN || header[..8] != IHAVEOPT.to_be_bytes() {
+        eprintln!("error: wrong option magic, disconnecting");
+        return Err(ServerError::Unsync)
+    }
+
+    let option = u32::from_be_bytes(header[8..12].try_into().unwrap());
+    let len = u32::from_be_bytes(header[12..16].try_into().unwrap());
+    Ok((option, len as usize))
+}
+
+impl Session {
+    pub fn new(exports: Arc<Exports>, chunk_size: u32, tls_mode: TlsMode, client_flags: u32) -> Result<Self, ServerError> {
+        //  the server MUST close the connection on flags it does not know
+        if client_flags & !CLIENT_FLAGS != 0 {
+            return Err(ServerError::ClientFlags(client_flags))
+        }
+
+        Ok ( Self {
+            chunk_size,
+            use_structured: false,
+            use_extended: false,
+            meta_contexts: Vec::new(),
+            no_zeroes: client_flags & NBD_FLAG_C_NO_ZEROES != 0,
+            block_size_asked: false,
+            tls_mode,
+            is_tls: false,
+            peer: None,
+            exports,
+            meta_export: None,
+            export: None
+        })
+    }
+
+    /// Oldstyle negotiation: the export is announced right away in the returned greeting
+    /// and there are no options, so the session is ready for transmission.
+    pub fn oldstyle(exports: Arc<Exports>, chunk_size: u32) -> Result<(Self, Vec<u8>), ServerError> {
+        let export = exports.default().ok_or_else(|| ServerError::ExportRefused(String::new()))?;
+
+        //  no way to start tls or say why, restricted exports are simply not offered
+        if export.access.is_restricted() {
+            return Err(ServerError::ExportRefused(export.name.clone()))
+        }
+
+        let flags = Self::transmission_flags(&export, false) as u32;  //  handshake flags are all zero
+        let greeting: Vec<u8> = NBDMAGIC.to_be_bytes().iter()
+            .chain(CLISERV_MAGIC.to_be_bytes().iter())
+            .chain(export.size().to_be_bytes().iter())
+            .chain(flags.to_be_bytes().iter())
+            .chain([0; 124].iter())
+            .copied().collect();
+
+        let mut session = Self::new(exports, chunk_size, TlsMode::Off, 0)?;
+        session.export = Some(export);
+
+        Ok((session, greeting))
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.export.is_some()
+    }
+
+    /// Error to reply with if the client may not use the export
+    fn check_access(&self, export: &Export) -> Option<opt::OptionReplyType> {
+        if !export.access.is_restricted() {
+            return None
+        }
+
+        match &self.peer {
+            Some(peer) if export.access.allows(peer) => None,
+            Some(_) => Some(opt::OptionReplyType::ErrPolicy),
+            None => Some(opt::OptionReplyType::ErrTlsReqd),
+        }
+    }
+
+    /// Looks up an export the client asked for by name, the empty name means the default one
+    fn resolve(&self, name: Option<&str>) -> Result<Arc<Export>, opt::OptionReplyType> {
+        let export = self.exports.get(name.unwrap_or_default())
+            .ok_or(opt::OptionReplyType::ErrUnknown)?;
+
+        match self.check_access(&export) {
+            Some(err) => Err(err),
+            None => Ok(export)
+        }
+    }
+
+    /// Answers an option whose data was too long to be read, the transport skips the data
+    pub fn oversized_option(&self, option: u32, out: &mut Vec<u8>) -> Result<Step, ServerError> {
+        //  EXPORT_NAME can't be answered with an error
+        if option == u32::from(opt::NbdOption::ExportName(None)) {
+            return Err(ServerError::ExportRefused(String::new()))
+        }
+
+        send_msg(out, opt::Reply::new(option, opt::OptionReplyType::ErrTooBig, None))?;
+        Ok(Step::Continue)
+    }
+
+    /// Answers one option, `raw` holds its type, length and data as sent after IHAVEOPT.
+    /// Replies are appended to `out` for the transport to send.
+    pub fn option(&mut self, raw: &[u8], out: &mut Vec<u8>) -> Result<Step, ServerError> {
+        let option = match opt::NbdOption::try_from(raw) {
+            Ok(option) => option,
+            Err(e) => {
+                eprintln!("option parse error: {:?}", e);
+
+                let option_type = raw.get(..4).map_or(0, |t| u32::from_be_bytes(t.try_into().unwrap()));
+                if option_type == u32::from(opt::NbdOption::ExportName(None)) {
+                    return Err(ServerError::ExportRefused(String::new()))
+                }
+
+                let err = match e {
+                    opt::OptionError::UnknownOption(_) => opt::OptionReplyType::ErrUnsup,
+                    _ => opt::OptionReplyType::ErrInvalid
+                };
+                send_msg(out, opt::Reply::new(option_type, err, None))?;
+                return Ok(Step::Continue)
+            }
+        };
+        eprintln!("got opt: {:?}", option);
+
+        //  FORCEDTLS: nothing but STARTTLS and ABORT before the upgrade
+        if self.tls_mode == TlsMode::Required && !self.is_tls {
+            match option {
+                opt::NbdOption::Starttls(_) | opt::NbdOption::Abort => {},
+
+                //  EXPORT_NAME can't be answered with an error
+                opt::NbdOption::ExportName(_) => return Err(ServerError::TlsRequired),
+
+                ot => {
+                    let reply = opt::Reply::new(ot.into(), opt::OptionReplyType::ErrTlsReqd, None);
+                    send_msg(out, reply)?;
+                    return Ok(Step::Continue)
+                }
+            }
+        }
+
+        match option {
+            opt::NbdOption::Info(name, info_requests) => {
+                let option = opt::NbdOption::Info(None, None).into();
+                self.block_size_asked |= info_requests.as_ref().is_some_and(|i| i.contains(&opt::Info::BlockSize));
+                let export = match self.resolve(name.as_deref()) {
+                    Ok(export) => export,
+                    Err(err) => {
+                        send_msg(out, opt::Reply::new(option, err, None))?;
+                        return Ok(Step::Continue)
+                    }
+                };
+
+                let mut infos = Self::get_info(option, export, self.use_structured, info_requests);
+                infos.try_for_each(|reply| send_msg(out, reply))?;
+
+                send_msg(out, opt::Reply::new(option, opt::OptionReplyType::Ack, None))?;
+            },
+
+            opt::NbdOption::Go(name, info_requests) => {
+                let option = opt::NbdOption::Go(None, None).into();
+
+                self.block_size_asked |= info_requests.as_ref().is_some_and(|i| i.contains(&opt::Info::BlockSize));
+                let export = match self.resolve(name.as_deref()) {
+                    Ok(export) => export,
+                    Err(err) => {
+                        send_msg(out, opt::Reply::new(option, err, None))?;
+                        return Ok(Step::Continue)
+                    }
+                };
+
+                //  sector-addressed devices are only served to clients that honour the constraints,
+                //  the others are told what they are to save a round trip
+                if export.block_size.min > 1 && !self.block_size_asked {
+                    let info = opt::Reply::new(option, opt::OptionReplyType::Info, Some(Self::block_size_info(&export.block_size)));
+                    send_msg(out, info)?;
+                    send_msg(out, opt::Reply::new(option, opt::OptionReplyType::ErrBlockSizeReqd, None))?;
+                    return Ok(Step::Continue)
+                }
+
+                let mut infos = Self::get_info(option, Arc::clone(&export), self.use_structured, info_requests);
+                infos.try_for_each(|reply| send_msg(out, reply))?;
+
+                send_msg(out, opt::Reply::new(option, opt::OptionReplyType::Ack, None))?;
+
+                self.select_export(export);
+                return Ok(Step::Transmission)
+            },
+
+            //  no option reply here, an unusable export can only be refused by hanging up
+            opt::NbdOption::ExportName(name) => {
+                let export = self.resolve(name.as_deref())
+                    .map_err(|_| ServerError::ExportRefused(name.unwrap_or_default()))?;
+
+                out.extend(export.size().to_be_bytes().iter()
+                    .chain(Self::transmission_flags(&export, self.use_structured).to_be_bytes().iter()));
+                if !self.no_zeroes {
+                    out.extend_from_slice(&[0; 124]);
+                }
+
+                self.select_export(export);
+                return Ok(Step::Transmission)
+            },
+
+            opt::NbdOption::StructuredReply(allow) => {             //  The client MUST NOT send any additional data
+                let reply = opt::Reply::new(                        //  with the option, and the server SHOULD reject
+                    opt::NbdOption::StructuredReply(false).into(),  //  a request that includes data with
+                    if allow { opt::OptionReplyType::Ack }          //  `NBD_REP_ERR_INVALID`
+                    else { opt::OptionReplyType::ErrInvalid },
+                    None
+                );
+
+                send_msg(out, reply)?;
+                self.use_structured = allow;
+            }
+
+            //  extended headers imply structured replies
+            opt::NbdOption::ExtendedHeaders(allow) => {
+                let reply = opt::Reply::new(
+                    opt::NbdOption::ExtendedHeaders(false).into(),
+                    if allow { opt::OptionReplyType::Ack }
+                    else { opt::OptionReplyType::ErrInvalid },
+                    None
+                );
+
+                send_msg(out, reply)?;
+                self.use_extended = allow;
+                self.use_structured |= allow;
+            }
+
+            opt::NbdOption::ListMetaContext(name, queries) => {
+                let option = opt::NbdOption::ListMetaContext(None, None).into();
+                let export = match self.resolve(name.as_deref()) {
+                    Ok(_) if !self.use_structured => Err(opt::OptionReplyType::ErrInvalid),
+                    res => res
+                };
+                let export = match export {
+                    Ok(export) => export,
+                    Err(err) => {
+                        send_msg(out, opt::Reply::new(option, err, None))?;
+                        return Ok(Step::Continue)
+                    }
+                };
+
+                //  context ids MUST be zero in list replies
+                let contexts = Registry::new(&export)
+                    .list(&queries.unwrap_or_default())
+                    .into_iter()
+                    .map(|ctx| (0, ctx))
+                    .collect::<Vec<_>>();
+
+                Self::send_meta_contexts(out, option, &contexts)?;
+            }
+
+            opt::NbdOption::SetMetaContext(name, queries) => {
+                let option = opt::NbdOption::SetMetaContext(None, None).into();
+                self.meta_contexts.clear();
+                self.meta_export = None;
+
+                let export = match self.resolve(name.as_deref()) {
+                    Ok(_) if !self.use_structured => Err(opt::OptionReplyType::ErrInvalid),
+                    res => res
+                };
+                let export = match export {
+                    Ok(export) => export,
+                    Err(err) => {
+                        send_msg(out, opt::Reply::new(option, err, None))?;
+                        return Ok(Step::Continue)
+                    }
+                };
+
+                self.meta_contexts = Registry::new(&export)
+                    .select(&queries.unwrap_or_default())
+                    .into_iter()
+                    .zip(1..)
+                    .map(|(ctx, id)| (id, ctx))
+                    .collect();
+
+                self.meta_export = Some(export);
+
+                Self::send_meta_contexts(out, option, &self.meta_contexts)?;
+            }
+
+            opt::NbdOption::List => {
+                //  exports the client can't access are not advertised
+                for export in self.exports.iter().filter(|e| self.check_access(e).is_none()) {
+                    let data = (export.name.len() as u32).to_be_bytes().iter()
+                        .chain(export.name.as_bytes())
+                        .chain(export.description.as_bytes())
+                        .copied()
+                        .collect();
+
+                    let reply = opt::Reply::new(
+                        opt::NbdOption::List.into(),
+                        opt::OptionReplyType::Server,
+                        Some(data)
+                    );
+
+                    send_msg(out, reply)?;
+                }
+
+                let reply = opt::Reply::new(
+                    opt::NbdOption::List.into(),
+                    opt::OptionReplyType::Ack,
+                    None
+                );
+
+                send_msg(out, reply)?;
+            }
+
+            opt::NbdOption::Starttls(allow) => {
+                let ack = allow && self.tls_mode != TlsMode::Off && !self.is_tls;
+                let reply = opt::Reply::new(
+                    opt::NbdOption::Starttls(false).into(),
+                    if ack { opt::OptionReplyType::Ack }
+                    else if self.tls_mode == TlsMode::Off { opt::OptionReplyType::ErrUnsup }
+                    else { opt::OptionReplyType::ErrInvalid },
+                    None
+                );
+
+                send_msg(out, reply)?;
+                if ack { return Ok(Step::StartTls) }
+            }
+
+            opt::NbdOption::Abort => {
+                let reply = opt::Reply::new(
+                    opt::NbdOption::Abort.into(),
+                    opt::OptionReplyType::Ack,
+                    None
+                );
+
+                send_msg(out, reply)?;
+                return Ok(Step::Abort)
+            }
+
+            ot => {
+                let reply = opt::Reply::new(
+                    ot.into(),
+                    opt::OptionReplyType::ErrUnsup,
+                    None
+                );
+
+                send_msg(out, reply)?;
+            },
+        }
+
+        Ok(Step::Continue)
+    }
+
+    /// Called after the transport finished the TLS handshake of an acknowledged STARTTLS
+    pub fn tls_started(&mut self, peer: Option<Peer>) {
+        self.is_tls = true;
+        self.peer = peer;
+
+        //  nothing negotiated in plaintext carries over
+        self.use_structured = false;
+        self.use_extended = false;
+        self.meta_contexts.clear();
+        self.meta_export = None;
+    }
+
+    /// Length of the request header, magic included
+    pub fn request_header_len(&self) -> usize {
+        if self.use_extended { 32 } else { 28 }
+    }
+
+    pub fn parse_request(&self, header: &[u8]) -> Result<req::Request, ServerError> {
+        // ?: consider moving magic check to request code
+        let expected_magic = if self.use_extended { req::EXTENDED_REQMAGIC } else { req::REQMAGIC };
+        if header.len() < 4 || header[..4] != expected_magic.to_be_bytes() {
+            eprintln!("error: wrong request magic, disconnecting");
+            return Err(ServerError::Unsync)
+        }
+
+        let request = if self.use_extended {
+            req::Request::from_extended(&header[4..])
+        } else {
+            req::Request::try_from(&header[4..])
+        };
+
+        request.map_err(|e| {
+            eprintln!("error: {:?}", e);
+            e.into()
+        })
+    }
+
+    //  payload has to be consumed even if the write is going to be rejected,
+    //  oversized ones are skipped without buffering
+    pub fn payload(&self, request: &req::Request) -> Payload {
+        match request.type_ {
+            req::RequestType::Write => {
+                let max = self.export.as_ref().map_or(0, |e| e.block_size.max);
+                if request.len <= max as u64 { Payload::Read(request.len as usize) }
+                else { Payload::Skip(request.len) }
+            },
+            _ => Payload::None
+        }
+    }
+
+    /// Requests that may only run once everything received before them has been replied to.
+    /// A flush has to cover every write the client saw completed, a resize moves the end
+    /// of the export under reads and writes, and nothing may be left unanswered on disconnect.
+    pub fn is_barrier(&self, request: &req::Request) -> bool {
+        matches!(request.type_, req::RequestType::Flush | req::RequestType::Resize | req::RequestType::Disc)
+    }
+
+    /// Carries out a request and returns its whole reply as it goes on the wire. Reads do
+    /// their I/O while the reply is built, so that happens here too: a bug in either fails
+    /// just that request, the client isn't left waiting on it.
+    pub fn reply(&self, request: req::Request) -> Vec<u8> {
+        let (offset, handle) = (request.offset, request.handle);
+        let mut reply = Vec::new();
+        let built = panic::catch_unwind(AssertUnwindSafe(|| self.handle(request).send(&mut reply)));
+
+        if !matches!(built, Ok(Ok(()))) {
+            reply.clear();
+            let failed = rpl::Reply::Simple(rpl::SimpleReply::new(5, handle, None));  // NBD_EIO
+            self.negotiated(failed, offset).send(&mut reply).expect("replies are built in memory");
+        }
+        reply
+    }
+
+    /// Carries out a request, the reply is in the form the client negotiated
+    fn handle(&self, request: req::Request) -> rpl::Reply {
+        let offset = request.offset;
+        self.negotiated(self.handle_request(request), offset)
+    }
+
+    fn negotiated(&self, reply: rpl::Reply, offset: u64) -> rpl::Reply {
+        //  simple replies are not allowed with extended headers
+        if !self.use_extended {
+            return reply
+        }
+
+        match reply {
+            rpl::Reply::Simple(reply) => rpl::Reply::Structured(
+                rpl::StructuredReply::from_simple(reply).with_extended_headers(offset)
+            ),
+            rpl::Reply::Structured(replies) => rpl::Reply::Structured(
+                replies.with_extended_headers(offset)
+            ),
+            disconnect => disconnect
+        }
+    }
+
+    fn handle_request(&self, request: req::Request) -> rpl::Reply {
+        let export = self.export.as_ref().expect("export is selected before transmission");
+
+        if let Some(error) = Self::check_block_size(export, &request) {
+            return rpl::Reply::Simple(rpl::SimpleReply::new(error, request.handle, None))
+        }
+
+        match request.type_ {
+            req::RequestType::Read => {
+                if !export.contains(request.offset, request.len) {
+                    return rpl::Reply::Simple(rpl::SimpleReply::new(22, request.handle, None))  // NBD_EINVAL
+                }
+                let len = request.len as u32;  //  capped at block_size.max by check_block_size
+                if self.use_structured {
+                    let dont_fragment = request.flags.dont_fragment;
+                    if dont_fragment && len > MAX_DF_PAYLOAD {
+                        return rpl::Reply::Simple(rpl::SimpleReply::new(75, request.handle, None))  // NBD_EOVERFLOW
+                    }
+
+                    rpl::Reply::Structured(
+                        rpl::StructuredReply::read_from_offset(
+                            Arc::clone(export), request.handle, request.offset, len, self.chunk_size, dont_fragment
+                        )
+                    )
+                } else {
+                    rpl::Reply::Simple( match export.read(request.offset, len as usize) {
+                        Ok(data) => rpl::SimpleReply::new(0, request.handle, Some(data)),
+                        Err(err) => rpl::SimpleReply::new(rpl::error_code(&err), request.handle, None)
+                    })
+                }
+            },
+            req::RequestType::Write => {
+                let error = if export.read_only {
+                    1  // NBD_EPERM
+                } else if !export.contains(request.offset, request.len) {
+                    28  // NBD_ENOSPC
+                } else {
+                    let data = request.data.as_deref().unwrap_or_default();
+                    //  FUA: the write has to reach non-volatile storage before we reply
+                    match export.write(data, request.offset).and_then(|_|
+                        if request.flags.fua { export.flush() } else { Ok(()) }
+                    ) {
+                        Ok(()) => 0,
+                        Err(err) => rpl::error_code(&err)
+                    }
+                };
+                rpl::Reply::Simple(rpl::SimpleReply::new(error, request.handle, None))
+            },
+            req::RequestType::Flush => {
+                let error = match export.flush() {
+                    Ok(()) => 0,
+                    Err(err) => rpl::error_code(&err)
+                };
+                rpl::Reply::Simple(rpl::SimpleReply::new(error, request.handle, None))
+            },
+            req::RequestType::Trim => {
+                let error = if export.read_only {
+                    1  // NBD_EPERM
+                } else if !export.contains(request.offset, request.len) {
+                    22  // NBD_EINVAL
+                } else {
+                    match export.trim(request.offset, request.len).and_then(|_|
+                        if request.flags.fua { export.flush() } else { Ok(()) }
+                    ) {
+                        Ok(()) => 0,
+                        Err(err) => rpl::error_code(&err)
+                    }
+                };
+                rpl::Reply::Simple(rpl::SimpleReply::new(error, request.handle, None))
+            },
+            req::RequestType::WriteZeroes => {
+                let error = if export.read_only {
+                    1  // NBD_EPERM
+                } else if !export.contains(request.offset, request.len) {
+                    28  // NBD_ENOSPC
+                } else {
+                    let flags = &request.flags;
+                    match export.write_zeroes(request.offset, request.len, flags.no_hole, flags.fast_zero)
+                        .and_then(|_| if flags.fua { export.flush() } else { Ok(()) })
+                    {
+                        Ok(()) => 0,
+                        Err(err) => rpl::error_code(&err)
+                    }
+                };
+                rpl::Reply::Simple(rpl::SimpleReply::new(error, request.handle, None))
+            },
+            req::RequestType::Cache => {
+                //  no command flags are defined for cache, the advertised command must reject them
+                let error = if request.flags.raw != 0
+                    || !export.contains(request.offset, request.len)
+                {
+                    22  // NBD_EINVAL
+                } else {
+                    match export.cache(request.offset, request.len) {
+                        Ok(()) => 0,
+                        Err(err) => rpl::error_code(&err)
+                    }
+                };
+                rpl::Reply::Simple(rpl::SimpleReply::new(error, request.handle, None))
+            },
+            req::RequestType::BlockStatus => {
+                //  every context needs at least one descriptor, and none of them may be empty
+                if !self.use_structured
+                    || self.meta_contexts.is_empty()
+                    || request.len == 0
+                    || !export.contains(request.offset, request.len)
+                {
+                    return rpl::Reply::Simple(rpl::SimpleReply::new(22, request.handle, None))  // NBD_EINVAL
+                }
+
+                let max = if request.flags.request_one { 1 } else { MAX_EXTENTS };
+                let statuses: std::io::Result<Vec<_>> = self.meta_contexts.iter()
+                    .map(|(id, ctx)| {
+                        let extents: Vec<(u64, u32)> = match ctx {
+                            MetaContext::BaseAllocation => export.extents(request.offset, request.len, max)?
+                                .into_iter()
+                                .map(|(len, hole)| (len, if hole { NBD_STATE_HOLE | NBD_STATE_ZERO } else { 0 }))
+                                .collect(),
+                            MetaContext::DirtyBitmap(name) => export.bitmap(name)
+                                .map(|bitmap| bitmap.extents(request.offset, request.len, max))
+                                .transpose()?
+                                .unwrap_or_default()
+                                .into_iter()
+                                .map(|(len, dirty)| (len, if dirty { NBD_STATE_DIRTY } else { 0 }))
+                                .collect(),
+                        };
+                        Ok((*id, extents))
+                    }).collect();
+
+                match statuses {
+                    Ok(statuses) => rpl::Reply::Structured(rpl::StructuredReply::block_status(request.handle, statuses, self.use_extended)),
+                    Err(err) => rpl::Reply::Simple(rpl::SimpleReply::new(rpl::error_code(&err), request.handle, None))
+                }
+            },
+            //  the resize extension passes the new size in the offset field
+            req::RequestType::Resize => {
+                let error = match export.resize(request.offset)
+                    .and_then(|_| if request.flags.fua { export.flush() } else { Ok(()) })
+                {
+                    Ok(()) => 0,
+                    Err(err) => rpl::error_code(&err)
+                };
+                rpl::Reply::Simple(rpl::SimpleReply::new(error, request.handle, None))
+            },
+            req::RequestType::Disc => rpl::Reply::Disconnect,
+        }
+    }
+
+    //  meta contexts only apply to the export they were set for
+    fn select_export(&mut self, export: Arc<Export>) {
+        if !self.meta_export.as_ref().is_some_and(|e| Arc::ptr_eq(e, &export)) {
+            self.meta_contexts.clear();
+        }
+        self.export = Some(export);
+    }
+
+    fn send_meta_contexts(out: &mut Vec<u8>, option: u32, contexts: &[(u32, MetaContext)]) -> std::io::Result<()> {
+        for (id, ctx) in contexts {
+            let data = id.to_be_bytes().iter()
+                .chain(ctx.name().as_bytes())
+                .copied()
+                .collect();
+
+            let reply = opt::Reply::new(option, opt::OptionReplyType::MetaContext, Some(data));
+            send_msg(out, reply)?;
+        }
+
+        let reply = opt::Reply::new(option, opt::OptionReplyType::Ack, None);
+        send_msg(out, reply)
+    }
+
+    //  alignment holds for every command addressing the export, the payload cap for reads and writes
+    fn check_block_size(export: &Export, request: &req::Request) -> Option<u32> {
+        let block_size = export.block_size;
+        match request.type_ {
+            req::RequestType::Read | req::RequestType::Write if request.len > block_size.max as u64 =>
+                Some(22),  // NBD_EINVAL
+            req::RequestType::Flush | req::RequestType::Disc => None,
+            _ if !block_size.is_aligned(request.offset, request.len) => Some(22),  // NBD_EINVAL
+            _ => None
+        }
+    }
+
+    fn transmission_flags(export: &Export, use_structured: bool) -> u16 {
+        let mut flags = NBD_FLAG_HAS_FLAGS;
+        if export.read_only {
+            flags |= NBD_FLAG_READ_ONLY;
+        } else {
+            flags |= NBD_FLAG_SEND_FLUSH | NBD_FLAG_SEND_FUA;
+            flags |= NBD_FLAG_SEND_WRITE_ZEROES | NBD_FLAG_SEND_FAST_ZERO;
+        }
+        if export.can_trim { flags |= NBD_FLAG_SEND_TRIM; }
+        if !export.read_only && !export.block_device { flags |= NBD_FLAG_SEND_RESIZE; }
+        flags |= NBD_FLAG_SEND_CACHE;
+        //  all connections to an export share its file, a flush on any of them covers every write
+        flags |= NBD_FLAG_CAN_MULTI_CONN;
+        if use_structured { flags |= NBD_FLAG_SEND_DF; }
+        flags
+    }
+
+    fn block_size_info(block_size: &BlockSize) -> Vec<u8> {
+        opt::Info::BlockSize.to_u16().unwrap()
+            .to_be_bytes().iter()
+            .chain(block_size.min.to_be_bytes().iter())
+            .chain(block_size.preferred.to_be_bytes().iter())
+            .chain(block_size.max.to_be_bytes().iter())
+            .copied().collect()
+    }
+
+    fn get_info(option: u32, export: Arc<Export>, use_structured: bool, info_requests: Option<Vec<opt::Info>>) -> impl Iterator<Item = opt::Reply> {
+        let export_size: u64 = export.size();
+        let block_size = export.block_size;
+        let export_name: String = export.name.clone();
+        let description: String = export.description.clone();
+        let transmission_flags: u16 = Self::transmission_flags(&export, use_structured);
+
+        let mut info_requests = info_requests.unwrap_or_default();
+        if !info_requests.contains(&opt::Info::Export) {
+            info_requests.insert(0, opt::Info::Export);
+        }
+
+        info_requests.into_iter()
+                .map(move |info_type| match info_type {
+                    opt::Info::Export =>
+                        opt::Info::Export.to_u16().unwrap()
+                            .to_be_bytes().iter()
+                            .chain(export_size.to_be_bytes().iter())
+                            .chain(transmission_flags.to_be_bytes().iter())
+                            .copied().collect(),
+
+                    opt::Info::Name =>
+                        opt::Info::Name.to_u16().unwrap()
+                            .to_be_bytes().iter()
+                            .chain(export_name.clone().into_bytes().iter())
+                            .copied().collect(),
+
+                    opt::Info::Description =>
+                        opt::Info::Description.to_u16().unwrap()
+                            .to_be_bytes().iter()
+                            .chain(description.as_bytes())
+                            .copied().collect(),
+
+                    opt::Info::BlockSize => Self::block_size_info(&block_size),
+
+                }).map(move |data| opt::Reply::new(
+                    option,
+                    opt::OptionReplyType::Info,
+                    Some(data)
+                ))
+    }
+}
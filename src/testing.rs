@@ -0,0 +1,24 @@
+//  Fixtures shared by the unit tests
+
+use std::path::PathBuf;
+
+/// A file in the temp dir named after the test and this process, removed again on drop
+pub struct ScratchFile(PathBuf);
+
+impl ScratchFile {
+    pub fn new(name: &str, contents: &[u8]) -> Self {
+        let path = std::env::temp_dir().join(format!("nbd-test-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+impl Drop for ScratchFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
@@ -0,0 +1,777 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+#[cfg(feature = "psk")]
+use openssl::ex_data::Index;
+#[cfg(feature = "psk")]
+use openssl::ssl::{ErrorCode, HandshakeError, Ssl, SslAcceptor, SslMethod, SslRef, SslStream};
+use ring::digest::{digest, SHA256};
+use rustls::pki_types::{CertificateDer, TrustAnchor, UnixTime};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use webpki::{EndEntityCert, KeyUsage};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate, X509Name};
+
+use crate::transport::{AsyncTransport, Transport};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TlsMode {
+    Off,
+    Optional,  //  SELECTIVETLS, exports with allow-* lists answer NBD_REP_ERR_TLS_REQD in plaintext
+    Required,  //  FORCEDTLS
+}
+
+impl std::str::FromStr for TlsMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(TlsMode::Off),
+            "optional" => Ok(TlsMode::Optional),
+            "required" => Ok(TlsMode::Required),
+            other => Err(format!("unknown tls mode: {}", other))
+        }
+    }
+}
+
+//  rustls has no support for external pre-shared keys, so PSK goes through openssl,
+//  which is only linked in with the `psk` feature
+#[derive(Clone)]
+enum Backend {
+    Certificate(Arc<ServerConfig>),
+    #[cfg(feature = "psk")]
+    Psk(Arc<SslAcceptor>, Index<Ssl, String>),  //  where the callback leaves the identity
+}
+
+#[derive(Clone)]
+pub struct Tls {
+    pub mode: TlsMode,
+    backend: Option<Backend>,
+    client_cas: Arc<ClientCas>,
+}
+
+//  trusted roots for client certificates, kept to tell which one a chain was verified against
+#[derive(Default)]
+struct ClientCas {
+    anchors: Vec<TrustAnchor<'static>>,
+    fingerprints: Vec<String>,  //  of the anchor at the same index
+}
+
+/// What the client proved about itself during the TLS handshake
+#[derive(Clone, Debug, Default)]
+pub struct Peer {
+    pub psk_identity: Option<String>,
+    pub cert_names: Vec<String>,       //  subject DN and SANs of the client certificate
+    pub ca_fingerprints: Vec<String>,  //  SHA-256 of the trusted root the certificate chains to
+}
+
+/// Per-export allow-lists, a client needs to match any entry. Empty lists leave the export open.
+#[derive(Clone, Debug, Default)]
+pub struct AccessPolicy {
+    pub psk_identities: Vec<String>,
+    pub cert_names: Vec<String>,
+    pub ca_fingerprints: Vec<String>,
+}
+
+impl AccessPolicy {
+    pub fn is_restricted(&self) -> bool {
+        !(self.psk_identities.is_empty() && self.cert_names.is_empty() && self.ca_fingerprints.is_empty())
+    }
+
+    pub fn allows(&self, peer: &Peer) -> bool {
+        peer.psk_identity.as_ref().is_some_and(|id| self.psk_identities.contains(id))
+            || peer.cert_names.iter().any(|name| self.cert_names.contains(name))
+            || peer.ca_fingerprints.iter().any(|fp| self.ca_fingerprints.contains(fp))
+    }
+}
+
+/// Lowercase hex without separators, so `AB:CD` and `abcd` compare equal
+pub fn normalize_fingerprint(fp: &str) -> String {
+    fp.chars().filter(|c| *c != ':').collect::<String>().to_lowercase()
+}
+
+impl Tls {
+    pub fn off() -> Self {
+        Self { mode: TlsMode::Off, backend: None, client_cas: Arc::default() }
+    }
+
+    /// With `client_ca_path` client certificates signed by those CAs are verified and
+    /// available for access checks. Clients without one are still let in.
+    pub fn with_certificate(mode: TlsMode, cert_path: &str, key_path: &str, client_ca_path: Option<&str>) -> io::Result<Self> {
+        if mode == TlsMode::Off {
+            return Ok(Self::off())
+        }
+
+        let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+            .collect::<io::Result<Vec<_>>>()?;
+        let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no private key found"))?;
+
+        let mut client_cas = ClientCas::default();
+        let builder = match client_ca_path {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(path)?)) {
+                    let cert = cert?;
+                    let anchor = webpki::anchor_from_trusted_cert(&cert)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    client_cas.anchors.push(anchor.to_owned());
+                    client_cas.fingerprints.push(fingerprint(&cert));
+                    roots.add(cert).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                }
+
+                let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                    .allow_unauthenticated()
+                    .build()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+                ServerConfig::builder().with_client_cert_verifier(verifier)
+            },
+            None => ServerConfig::builder().with_no_client_auth()
+        };
+
+        let config = builder
+            .with_single_cert(certs, key)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        Ok(Self { 
+            mode, 
+            backend: Some(Backend::Certificate(Arc::new(config))), 
+            client_cas: Arc::new(client_cas) 
+        })
+    }
+
+    /// TLS-PSK with a keys file of `identity:hex-key` lines, as used by qemu and nbdkit.
+    /// Handshakes with identities missing from the file fail.
+    #[cfg(feature = "psk")]
+    pub fn with_psk(mode: TlsMode, keys_path: &str) -> io::Result<Self> {
+        if mode == TlsMode::Off {
+            return Ok(Self::off())
+        }
+
+        let keys = read_psk_keys(keys_path)?;
+
+        //  openssl only reports the identity of TLS 1.2 sessions, so it's kept by hand
+        let identity_index = Ssl::new_ex_index::<String>()?;
+
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+        builder.set_cipher_list("PSK")?;  //  TLS 1.2 suites, 1.3 ones work with PSK as is
+        builder.set_psk_server_callback(move |ssl, identity, psk| {
+            let key = identity
+                .and_then(|id| std::str::from_utf8(id).ok())
+                .and_then(|id| keys.get_key_value(id));
+
+            match key {
+                Some((id, key)) if key.len() <= psk.len() => {
+                    ssl.set_ex_data(identity_index, id.clone());
+                    psk[..key.len()].copy_from_slice(key);
+                    Ok(key.len())
+                },
+                _ => Ok(0)  //  unknown identity, handshake is aborted
+            }
+        });
+
+        Ok(Self { 
+            mode, 
+            backend: Some(Backend::Psk(Arc::new(builder.build()), identity_index)), 
+            client_cas: Arc::default() 
+        })
+    }
+
+    #[cfg(not(feature = "psk"))]
+    pub fn with_psk(_mode: TlsMode, _keys_path: &str) -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "built without TLS-PSK support, rebuild with --features psk"))
+    }
+
+    /// Refuses allow-lists no client could match under this configuration, which would
+    /// otherwise leave the export silently unreachable
+    pub fn check_policy(&self, export: &str, policy: &AccessPolicy) -> io::Result<()> {
+        let unreachable = |msg: String| Err(io::Error::new(
+            io::ErrorKind::InvalidInput, format!("export {:?}: {}", export, msg)
+        ));
+
+        #[cfg(feature = "psk")]
+        let psk = matches!(self.backend, Some(Backend::Psk(..)));
+        #[cfg(not(feature = "psk"))]
+        let psk = false;
+        if !policy.psk_identities.is_empty() && !psk {
+            return unreachable("allow-psk-identity needs tls with --tls-psk".to_owned())
+        }
+
+        let client_certs = matches!(self.backend, Some(Backend::Certificate(_))) && !self.client_cas.anchors.is_empty();
+        let certs_listed = !policy.cert_names.is_empty() || !policy.ca_fingerprints.is_empty();
+        if certs_listed && !client_certs {
+            return unreachable("allow-cert and allow-ca-fingerprint need tls with --tls-client-ca".to_owned())
+        }
+        if let Some(fp) = policy.ca_fingerprints.iter().find(|fp| !self.client_cas.fingerprints.contains(fp)) {
+            return unreachable(format!("allow-ca-fingerprint {} is not one of the --tls-client-ca certificates", fp))
+        }
+
+        Ok(())
+    }
+
+    /// Identities of the client on an upgraded stream, `None` for plain connections
+    pub fn peer<T: Transport>(&self, stream: &Stream<T>) -> Option<Peer> {
+        match stream {
+            Stream::Plain(_) => None,
+            #[cfg(feature = "psk")]
+            Stream::Psk(s) => Some(self.psk_peer(s.ssl())),
+            Stream::Tls(s) => Some(self.cert_peer(s.conn.peer_certificates())),
+        }
+    }
+
+    /// Same as `peer`, for connections served by the async server
+    pub fn async_peer<T: AsyncTransport>(&self, stream: &AsyncStream<T>) -> Option<Peer> {
+        match stream {
+            AsyncStream::Plain(_) => None,
+            #[cfg(feature = "psk")]
+            AsyncStream::Psk(s) => Some(self.psk_peer(s.ssl())),
+            AsyncStream::Tls(s) => Some(self.cert_peer(s.get_ref().1.peer_certificates())),
+        }
+    }
+
+    fn cert_peer(&self, chain: Option<&[CertificateDer]>) -> Peer {
+        let mut peer = Peer::default();
+        let (leaf, intermediates) = match chain {
+            Some([leaf, intermediates @ ..]) => (leaf, intermediates),
+            _ => return peer
+        };
+
+        //  the chain was verified by rustls, parsing it can't really fail
+        let cert = match X509Certificate::from_der(leaf) {
+            Ok((_, cert)) => cert,
+            Err(_) => return peer
+        };
+
+        peer.cert_names.push(format_name(cert.subject()));
+        if let Ok(Some(sans)) = cert.subject_alternative_name() {
+            peer.cert_names.extend(sans.value.general_names.iter().filter_map(|name| match name {
+                GeneralName::DNSName(s) | GeneralName::RFC822Name(s) | GeneralName::URI(s) => Some(s.to_string()),
+                _ => None
+            }));
+        }
+
+        //  the client may send any certificates along, only the root its path was verified
+        //  against says who issued it, so the path is built again to find that root
+        let anchors = &self.client_cas.anchors;
+        let algorithms = rustls::crypto::ring::default_provider().signature_verification_algorithms.all;
+        let leaf = match EndEntityCert::try_from(leaf) {
+            Ok(leaf) => leaf,
+            Err(_) => return peer
+        };
+        let path = leaf.verify_for_usage(
+            algorithms, anchors, intermediates, UnixTime::now(), KeyUsage::client_auth(), None, None
+        );
+        if let Some(i) = path.ok().and_then(|path| anchors.iter().position(|anchor| std::ptr::eq(anchor, path.anchor()))) {
+            peer.ca_fingerprints.push(self.client_cas.fingerprints[i].clone());
+        }
+
+        peer
+    }
+
+    #[cfg(feature = "psk")]
+    fn psk_peer(&self, ssl: &SslRef) -> Peer {
+        let psk_identity = match &self.backend {
+            Some(Backend::Psk(_, index)) => ssl.ex_data(*index).cloned(),
+            _ => None
+        };
+
+        Peer { psk_identity, ..Peer::default() }
+    }
+}
+
+fn fingerprint(cert: &CertificateDer) -> String {
+    digest(&SHA256, cert).as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+//  `CN=alice,O=team-a` in certificate order
+fn format_name(name: &X509Name) -> String {
+    let registry = x509_parser::objects::oid_registry();
+    name.iter_attributes()
+        .map(|attr| {
+            let key = x509_parser::objects::oid2abbrev(attr.attr_type(), registry).unwrap_or("?");
+            let value = attr.as_str().unwrap_or_default();
+            format!("{}={}", key, value)
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+//  parsed in every build so the tests cover it, only the psk feature uses the keys
+#[cfg_attr(not(feature = "psk"), allow(dead_code))]
+fn read_psk_keys(path: &str) -> io::Result<HashMap<String, Vec<u8>>> {
+    let invalid = |line: &str| io::Error::new(
+        io::ErrorKind::InvalidData, format!("bad psk line (expected identity:hex-key): {}", line)
+    );
+
+    let mut keys = HashMap::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') { continue }
+
+        let (identity, key) = line.split_at(line.find(':').ok_or_else(|| invalid(line))?);
+        let key = &key[1..];
+        if key.len() % 2 != 0 { return Err(invalid(line)) }
+
+        let key = (0..key.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&key[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| invalid(line))?;
+
+        keys.insert(identity.to_owned(), key);
+    }
+
+    Ok(keys)
+}
+
+pub enum Stream<T: Transport> {
+    Plain(T),
+    Tls(Box<StreamOwned<ServerConnection, T>>),
+    #[cfg(feature = "psk")]
+    Psk(Box<SslStream<Bridge<T>>>),
+}
+
+impl<T: Transport> Stream<T> {
+    /// Halves to read requests and write replies with from different threads, or the
+    /// stream back if its transport can't be read and written at once.
+    pub fn split(self) -> io::Result<Result<Halves<T>, Self>> {
+        match self {
+            Stream::Plain(sock) => Ok(match sock.try_clone()? {
+                Some(writer) => Ok((Half::Plain(sock), Half::Plain(writer))),
+                None => Err(Stream::Plain(sock))
+            }),
+            Stream::Tls(stream) => {
+                let (conn, sock) = stream.into_parts();
+                Ok(match sock.try_clone()? {
+                    Some(writer) => Ok(SplitTls::pair(Box::new(conn), sock, writer)),
+                    None => Err(Stream::Tls(Box::new(StreamOwned::new(conn, sock))))
+                })
+            },
+            #[cfg(feature = "psk")]
+            Stream::Psk(mut stream) => {
+                let sock = stream.get_mut().sock.take().expect("the bridge keeps its socket until a split");
+                Ok(match sock.try_clone()? {
+                    Some(writer) => Ok(SplitTls::pair(stream, sock, writer)),
+                    None => {
+                        stream.get_mut().sock = Some(sock);
+                        Err(Stream::Psk(stream))
+                    }
+                })
+            },
+        }
+    }
+
+    /// Upgrades a plain connection, completing the handshake before returning.
+    pub fn start_tls(self, tls: &Tls) -> io::Result<Self> {
+        let backend = tls.backend.as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "tls is not configured"))?;
+
+        match (self, backend) {
+            (Stream::Plain(mut sock), Backend::Certificate(config)) => {
+                let mut conn = ServerConnection::new(Arc::clone(config))
+                    .map_err(io::Error::other)?;
+                while conn.is_handshaking() {
+                    conn.complete_io(&mut sock)?;
+                }
+
+                Ok(Stream::Tls(Box::new(StreamOwned::new(conn, sock))))
+            },
+            #[cfg(feature = "psk")]
+            (Stream::Plain(sock), Backend::Psk(acceptor, _)) => {
+                let stream = acceptor.accept(Bridge::new(sock)).map_err(|e| {
+                    let reason = match e {
+                        HandshakeError::SetupFailure(e) => e.to_string(),
+                        HandshakeError::Failure(s) | HandshakeError::WouldBlock(s) => s.error().to_string(),
+                    };
+                    io::Error::new(io::ErrorKind::PermissionDenied, reason)
+                })?;
+
+                Ok(Stream::Psk(Box::new(stream)))
+            },
+            _ => Err(io::Error::other("tls is already started"))
+        }
+    }
+}
+
+impl<T: Transport> Read for Stream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.read(buf),
+            Stream::Tls(s) => s.read(buf),
+            #[cfg(feature = "psk")]
+            Stream::Psk(s) => s.read(buf),
+        }
+    }
+}
+
+impl<T: Transport> Write for Stream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.write(buf),
+            Stream::Tls(s) => s.write(buf),
+            #[cfg(feature = "psk")]
+            Stream::Psk(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(s) => s.flush(),
+            Stream::Tls(s) => s.flush(),
+            #[cfg(feature = "psk")]
+            Stream::Psk(s) => s.flush(),
+        }
+    }
+}
+
+/// Reading and writing side of a split `Stream`
+pub type Halves<T> = (Half<T>, Half<T>);
+
+/// One side of a `Stream` split between a thread reading requests and one writing replies
+pub enum Half<T: Transport> {
+    Plain(T),
+    Tls(SplitTls<T>),
+}
+
+impl<T: Transport> Read for Half<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Half::Plain(s) => s.read(buf),
+            Half::Tls(s) => s.read(buf),
+        }
+    }
+}
+
+impl<T: Transport> Write for Half<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Half::Plain(s) => s.write(buf),
+            Half::Tls(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Half::Plain(s) => s.flush(),
+            Half::Tls(s) => s.flush(),
+        }
+    }
+}
+
+//  ciphertext read from the socket at a time, one full record
+const TLS_RECORD_LEN: usize = 16 << 10;
+
+/// Half of a TLS session shared with the other side of the split. The socket is read
+/// and written outside the session lock, so a half waiting on the client doesn't hold
+/// up the other one.
+pub struct SplitTls<T> {
+    shared: Arc<SharedSession>,
+    sock: T,
+    incoming: Vec<u8>,
+    unfed: Range<usize>,  //  part of `incoming` the session hasn't taken yet
+}
+
+struct SharedSession {
+    session: Mutex<Box<dyn TlsSession>>,
+    sending: Mutex<()>,  //  ciphertext goes out in the order the session produced it
+}
+
+impl<T: Transport> SplitTls<T> {
+    fn pair(session: Box<dyn TlsSession>, reader: T, writer: T) -> Halves<T> {
+        let shared = Arc::new(SharedSession { session: Mutex::new(session), sending: Mutex::new(()) });
+        (
+            Half::Tls(Self { shared: Arc::clone(&shared), sock: reader, incoming: Vec::new(), unfed: 0..0 }),
+            Half::Tls(Self { shared, sock: writer, incoming: Vec::new(), unfed: 0..0 }),
+        )
+    }
+
+    fn send_outgoing(&mut self) -> io::Result<()> {
+        let _sending = self.shared.sending.lock().unwrap();
+        let outgoing = self.shared.session.lock().unwrap().take_outgoing()?;
+        self.sock.write_all(&outgoing)?;
+        self.sock.flush()
+    }
+}
+
+impl<T: Transport> Read for SplitTls<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.incoming.resize(TLS_RECORD_LEN, 0);
+
+        loop {
+            if let Some(n) = self.shared.session.lock().unwrap().read_plain(buf)? {
+                return Ok(n)
+            }
+
+            if self.unfed.is_empty() {
+                let n = self.sock.read(&mut self.incoming)?;
+                if n == 0 { return Ok(0) }
+                self.unfed = 0..n;
+            }
+
+            let (fed, owed) = {
+                let mut session = self.shared.session.lock().unwrap();
+                let fed = session.receive(&self.incoming[self.unfed.clone()]);
+                (fed, session.has_outgoing())
+            };
+            //  alerts and key updates answering what just came in
+            if owed {
+                let sent = self.send_outgoing();
+                self.unfed.start += fed?;
+                sent?;
+            } else {
+                self.unfed.start += fed?;
+            }
+        }
+    }
+}
+
+impl<T: Transport> Write for SplitTls<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.shared.session.lock().unwrap().write_plain(buf)?;
+        self.send_outgoing()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_outgoing()
+    }
+}
+
+/// A TLS session fed ciphertext by hand instead of driving the socket itself
+trait TlsSession: Send {
+    /// `None` until more ciphertext comes in, `Some(0)` once the client closed the session
+    fn read_plain(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>>;
+    fn write_plain(&mut self, buf: &[u8]) -> io::Result<usize>;
+    /// Takes in ciphertext, returns how much of it was used. The rest is offered again
+    /// once the plaintext it already made is read.
+    fn receive(&mut self, ciphertext: &[u8]) -> io::Result<usize>;
+    fn has_outgoing(&self) -> bool;
+    fn take_outgoing(&mut self) -> io::Result<Vec<u8>>;
+}
+
+impl TlsSession for ServerConnection {
+    fn read_plain(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        match self.reader().read(buf) {
+            Ok(n) => Ok(Some(n)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    fn write_plain(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer().write(buf)
+    }
+
+    //  rustls refuses ciphertext once it holds a record's worth of plaintext
+    fn receive(&mut self, ciphertext: &[u8]) -> io::Result<usize> {
+        let mut rest = ciphertext;
+        while !rest.is_empty() {
+            self.read_tls(&mut rest)?;
+            let state = self.process_new_packets().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if state.plaintext_bytes_to_read() > 0 { break }
+        }
+        Ok(ciphertext.len() - rest.len())
+    }
+
+    fn has_outgoing(&self) -> bool {
+        self.wants_write()
+    }
+
+    fn take_outgoing(&mut self) -> io::Result<Vec<u8>> {
+        let mut outgoing = Vec::new();
+        while self.wants_write() {
+            self.write_tls(&mut outgoing)?;
+        }
+        Ok(outgoing)
+    }
+}
+
+/// Socket under an openssl session. The handshake runs on it directly, once the session
+/// is split the socket moves to the halves and openssl only sees these buffers.
+#[cfg(feature = "psk")]
+pub struct Bridge<T> {
+    sock: Option<T>,
+    incoming: Vec<u8>,
+    outgoing: Vec<u8>,
+}
+
+#[cfg(feature = "psk")]
+impl<T> Bridge<T> {
+    fn new(sock: T) -> Self {
+        Self { sock: Some(sock), incoming: Vec::new(), outgoing: Vec::new() }
+    }
+}
+
+#[cfg(feature = "psk")]
+impl<T: Read> Read for Bridge<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.sock {
+            Some(sock) => sock.read(buf),
+            None if self.incoming.is_empty() => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+            None => {
+                let n = buf.len().min(self.incoming.len());
+                buf[..n].copy_from_slice(&self.incoming[..n]);
+                self.incoming.drain(..n);
+                Ok(n)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "psk")]
+impl<T: Write> Write for Bridge<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.sock {
+            Some(sock) => sock.write(buf),
+            None => {
+                self.outgoing.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.sock {
+            Some(sock) => sock.flush(),
+            None => Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "psk")]
+impl<T: Transport> TlsSession for SslStream<Bridge<T>> {
+    fn read_plain(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        match self.ssl_read(buf) {
+            Ok(n) => Ok(Some(n)),
+            Err(e) if e.code() == ErrorCode::WANT_READ => Ok(None),
+            Err(e) if e.code() == ErrorCode::ZERO_RETURN => Ok(Some(0)),
+            Err(e) => Err(e.into_io_error().unwrap_or_else(io::Error::other))
+        }
+    }
+
+    fn write_plain(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.ssl_write(buf).map_err(|e| e.into_io_error().unwrap_or_else(io::Error::other))
+    }
+
+    fn receive(&mut self, ciphertext: &[u8]) -> io::Result<usize> {
+        self.get_mut().incoming.extend_from_slice(ciphertext);
+        Ok(ciphertext.len())
+    }
+
+    fn has_outgoing(&self) -> bool {
+        !self.get_ref().outgoing.is_empty()
+    }
+
+    fn take_outgoing(&mut self) -> io::Result<Vec<u8>> {
+        Ok(std::mem::take(&mut self.get_mut().outgoing))
+    }
+}
+
+/// Connection of the async server, upgraded the same way as `Stream`
+pub enum AsyncStream<T> {
+    Plain(T),
+    Tls(Box<tokio_rustls::server::TlsStream<T>>),
+    #[cfg(feature = "psk")]
+    Psk(Box<tokio_openssl::SslStream<T>>),
+}
+
+impl<T: AsyncTransport> AsyncStream<T> {
+    pub async fn start_tls(self, tls: &Tls) -> io::Result<Self> {
+        let backend = tls.backend.as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "tls is not configured"))?;
+
+        match (self, backend) {
+            (AsyncStream::Plain(sock), Backend::Certificate(config)) => {
+                let stream = tokio_rustls::TlsAcceptor::from(Arc::clone(config))
+                    .accept(sock).await?;
+
+                Ok(AsyncStream::Tls(Box::new(stream)))
+            },
+            #[cfg(feature = "psk")]
+            (AsyncStream::Plain(sock), Backend::Psk(acceptor, _)) => {
+                let ssl = Ssl::new(acceptor.context())?;
+                let mut stream = tokio_openssl::SslStream::new(ssl, sock)?;
+                Pin::new(&mut stream).accept().await
+                    .map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e.to_string()))?;
+
+                Ok(AsyncStream::Psk(Box::new(stream)))
+            },
+            _ => Err(io::Error::other("tls is already started"))
+        }
+    }
+}
+
+impl<T: AsyncTransport> AsyncRead for AsyncStream<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            AsyncStream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(feature = "psk")]
+            AsyncStream::Psk(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<T: AsyncTransport> AsyncWrite for AsyncStream<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            AsyncStream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            AsyncStream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(feature = "psk")]
+            AsyncStream::Psk(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStream::Plain(s) => Pin::new(s).poll_flush(cx),
+            AsyncStream::Tls(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(feature = "psk")]
+            AsyncStream::Psk(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            AsyncStream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(feature = "psk")]
+            AsyncStream::Psk(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchFile;
+
+    #[test]
+    fn reads_psk_keys() {
+        let keys = ScratchFile::new("good.psk", b"# identity:key\n\nalice:00ff10\n  bob:AbCd  \n");
+        let keys = read_psk_keys(keys.path()).unwrap();
+
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys["alice"], [0x00, 0xff, 0x10]);
+        assert_eq!(keys["bob"], [0xab, 0xcd]);
+    }
+
+    #[test]
+    fn rejects_bad_psk_lines() {
+        for (test, line) in [("colon", "alice"), ("odd", "alice:abc"), ("hex", "alice:zz")] {
+            let keys = ScratchFile::new(&format!("{}.psk", test), line.as_bytes());
+            let error = read_psk_keys(keys.path()).unwrap_err();
+
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert!(error.to_string().ends_with(line));
+        }
+    }
+}
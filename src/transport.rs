@@ -0,0 +1,59 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Byte stream a `Server` runs a session over. Anything readable and writable works,
+/// `impl Transport for Pipe {}` is enough for an in-memory one.
+pub trait Transport: Read + Write + Send + 'static {
+    /// Second handle to write replies with while another thread reads requests.
+    /// Without one requests are handled one at a time.
+    fn try_clone(&self) -> io::Result<Option<Self>> where Self: Sized {
+        Ok(None)
+    }
+}
+
+impl Transport for TcpStream {
+    fn try_clone(&self) -> io::Result<Option<Self>> {
+        TcpStream::try_clone(self).map(Some)
+    }
+}
+
+impl Transport for UnixStream {
+    fn try_clone(&self) -> io::Result<Option<Self>> {
+        UnixStream::try_clone(self).map(Some)
+    }
+}
+
+/// The process' stdin and stdout as one connection, for running under inetd, a systemd
+/// socket unit or `nbd-client` over ssh
+pub struct Stdio;
+
+impl Read for Stdio {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::stdin().read(buf)
+    }
+}
+
+impl Write for Stdio {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stdout().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+//  stdin and stdout are separate descriptors, reading and writing never meet
+impl Transport for Stdio {
+    fn try_clone(&self) -> io::Result<Option<Self>> {
+        Ok(Some(Stdio))
+    }
+}
+
+/// Byte stream an `AsyncServer` runs a session over
+pub trait AsyncTransport: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> AsyncTransport for T {}